@@ -3,3 +3,11 @@ pub const GLOBAL_STATE_SEED: &[u8] = b"GLOBAL_STATE_SEED";
 pub const USER_STATE_SEED: &[u8] = b"USER_STATE_SEED";
 
 pub const VAULT_SEED: &[u8] = b"VAULT_SEED";
+
+pub const MAX_BOND_AMOUNT: u64 = 5_000_000_000;
+
+pub const MAX_BOND_LAMPORTS: u64 = 50_000_000_000;
+
+pub const REFERRER_STATE_SEED: &[u8] = b"REFERRER_STATE_SEED";
+
+pub const MAX_REFERRAL_BPS: u64 = 1_000;
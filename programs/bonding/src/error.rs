@@ -24,5 +24,23 @@ pub enum BondingError {
     NoBond,
 
     #[msg("Out of vesting period")]
-    OutOfVestingPeriod
+    OutOfVestingPeriod,
+
+    #[msg("User state belongs to another user")]
+    InvalidUserState,
+
+    #[msg("Cannot refer yourself")]
+    SelfReferral,
+
+    #[msg("Referral bonus exceeds the maximum")]
+    InvalidReferralBps,
+
+    #[msg("Account is not the global state")]
+    InvalidGlobalState,
+
+    #[msg("Signer is not the global state authority")]
+    InvalidAuthority,
+
+    #[msg("Swap returned less than the bond requires")]
+    SlippageExceeded
 }
@@ -1,11 +1,15 @@
 use std::mem::size_of;
 use std::str::FromStr;
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use anchor_lang::system_program;
 use anchor_spl::{
-    token::{self, Mint, Token, TokenAccount, Transfer},
+    token::Token,
+    token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked, SyncNative},
     associated_token::AssociatedToken
 };
-use amm_anchor::SwapBaseIn;
+use anchor_spl::token::spl_token::native_mint;
+use amm_anchor::{SwapBaseIn, SwapBaseOut};
 
 use crate::{states::*, error::*, constants::*};
 
@@ -23,19 +27,20 @@ pub struct InitializeLfg<'info> {
     )]
     pub global_state: Account<'info, GlobalState>,
 
-    pub lfg_token_mint: Box<Account<'info, Mint>>,
+    pub lfg_token_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         init,
         token::mint = lfg_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
         seeds = [VAULT_SEED, lfg_token_mint.key().as_ref()],
         bump,
         payer = authority,
     )]
-    pub lfg_vault: Box<Account<'info, TokenAccount>>,
+    pub lfg_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -53,19 +58,20 @@ pub struct InitializeUSDC<'info> {
     )]
     pub global_state: Account<'info, GlobalState>,
 
-    pub usdc_token_mint: Box<Account<'info, Mint>>,
+    pub usdc_token_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         init,
         token::mint = usdc_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
         seeds = [VAULT_SEED, usdc_token_mint.key().as_ref()],
         bump,
         payer = authority,
     )]
-    pub usdc_vault: Box<Account<'info, TokenAccount>>,
+    pub usdc_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -81,6 +87,9 @@ pub fn initialize_lfg(ctx: Context<InitializeLfg>) -> Result<()> {
     global_state.start_time = 5 * 86400;
     global_state.vesting_time = 5 * 86400;
     global_state.rebase_ratio = 50;
+    global_state.referral_bps = 0;
+    global_state.referral_cap = 0;
+    global_state.referral_paid = 0;
 
     Ok(())
 }
@@ -117,6 +126,30 @@ pub fn init_user_state(ctx: Context<InitUserState>) -> Result<()> {
     Ok(())
 }
 
+#[derive(Accounts)]
+pub struct InitReferrerState<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    #[account(
+        init,
+        seeds = [REFERRER_STATE_SEED, referrer.key().as_ref()],
+        bump,
+        payer = referrer,
+        space = 8 + size_of::<ReferrerState>()
+    )]
+    pub referrer_state: Account<'info, ReferrerState>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>
+}
+
+pub fn init_referrer_state(ctx: Context<InitReferrerState>) -> Result<()> {
+    ctx.accounts.referrer_state.referrer = ctx.accounts.referrer.key();
+
+    Ok(())
+}
+
 #[derive(Accounts)]
 pub struct SetBump<'info> {
     #[account(mut)]
@@ -249,6 +282,90 @@ pub fn set_vesting_time(ctx: Context<SetVestingTime>, new_vesting_time: u64) ->
     Ok(())
 }
 
+#[derive(Accounts)]
+pub struct SetReferralConfig<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_STATE_SEED],
+        bump,
+        has_one = authority
+    )]
+    pub global_state: Account<'info, GlobalState>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>
+}
+
+pub fn set_referral_config(ctx: Context<SetReferralConfig>, referral_bps: u64, referral_cap: u64) -> Result<()> {
+    let global_state = &mut ctx.accounts.global_state;
+
+    require!(referral_bps <= MAX_REFERRAL_BPS, BondingError::InvalidReferralBps);
+
+    global_state.referral_bps = referral_bps;
+    global_state.referral_cap = referral_cap;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MigrateGlobalState<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: may still hold an older, shorter `GlobalState`, which does not
+    /// deserialize, so `migrate_global_state` checks it by hand.
+    #[account(
+        mut,
+        seeds = [GLOBAL_STATE_SEED],
+        bump
+    )]
+    pub global_state: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Grows a `GlobalState` created before fields were appended to it to the
+/// current size. The new fields read as zero, which leaves referral bonuses
+/// off until `set_referral_config` is called.
+pub fn migrate_global_state(ctx: Context<MigrateGlobalState>) -> Result<()> {
+    let accts = ctx.accounts;
+    let global_state = accts.global_state.to_account_info();
+
+    require_keys_eq!(*global_state.owner, crate::ID, BondingError::InvalidGlobalState);
+    {
+        let data = global_state.try_borrow_data()?;
+        require!(
+            data.len() >= 8 + 32 && data[..8] == GlobalState::DISCRIMINATOR,
+            BondingError::InvalidGlobalState
+        );
+        let authority = Pubkey::try_from(&data[8..8 + 32]).map_err(|_| BondingError::InvalidGlobalState)?;
+        require_keys_eq!(authority, accts.authority.key(), BondingError::InvalidAuthority);
+    }
+
+    let new_len = 8 + size_of::<GlobalState>();
+    if global_state.data_len() < new_len {
+        let lamports = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(global_state.lamports());
+        if lamports > 0 {
+            let cpi_ctx = CpiContext::new(
+                accts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: accts.authority.to_account_info(),
+                    to: global_state.clone(),
+                },
+            );
+            system_program::transfer(cpi_ctx, lamports)?;
+        }
+        global_state.realloc(new_len, true)?;
+    }
+
+    Ok(())
+}
+
 #[derive(Accounts)]
 pub struct SetOpenBond<'info> {
     #[account(mut)]
@@ -287,24 +404,26 @@ pub struct RecoverTreasuryTokens<'info> {
     )]
     pub global_state: Account<'info, GlobalState>,
 
-    pub usdc_token_mint: Box<Account<'info, Mint>>,
+    pub usdc_token_mint: Box<InterfaceAccount<'info, Mint>>,
     
     #[account(
         mut,
         token::mint = usdc_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
     )]
-    pub usdc_vault: Box<Account<'info, TokenAccount>>, 
+    pub usdc_vault: Box<InterfaceAccount<'info, TokenAccount>>, 
 
     #[account(
         init_if_needed,
         payer = authority,
         associated_token::mint = usdc_token_mint,
         associated_token::authority = authority,
+        associated_token::token_program = token_program,
     )]
-    pub ata_to: Box<Account<'info, TokenAccount>>,
+    pub ata_to: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
@@ -322,14 +441,15 @@ pub fn recover_treasury_tokens(ctx: Context<RecoverTreasuryTokens>) -> Result<()
     require!(global_state.bump > 0 || ctx.accounts.authority.key().eq(&dev_address), BondingError::OutOfVestingPeriod);
 
     let cpi_program = ctx.accounts.token_program.to_account_info();
-    let cpi_accounts = Transfer {
+    let cpi_accounts = TransferChecked {
         from: ctx.accounts.usdc_vault.to_account_info(),
+        mint: ctx.accounts.usdc_token_mint.to_account_info(),
         to: ctx.accounts.ata_to.to_account_info(),
         authority: ctx.accounts.global_state.to_account_info(),
     };
     let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
 
-    token::transfer(cpi_ctx.with_signer(global_state_seed), balance as u64)?;
+    token_interface::transfer_checked(cpi_ctx.with_signer(global_state_seed), balance, ctx.accounts.usdc_token_mint.decimals)?;
 
     Ok(())
 }
@@ -346,24 +466,26 @@ pub struct RecoverMainTokens<'info> {
     )]
     pub global_state: Account<'info, GlobalState>,
 
-    pub lfg_token_mint: Box<Account<'info, Mint>>,
+    pub lfg_token_mint: Box<InterfaceAccount<'info, Mint>>,
     
     #[account(
         mut,
         token::mint = lfg_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
     )]
-    pub lfg_vault: Box<Account<'info, TokenAccount>>, 
+    pub lfg_vault: Box<InterfaceAccount<'info, TokenAccount>>, 
 
     #[account(
         init_if_needed,
         payer = authority,
         associated_token::mint = lfg_token_mint,
         associated_token::authority = authority,
+        associated_token::token_program = token_program,
     )]
-    pub ata_to: Box<Account<'info, TokenAccount>>,
+    pub ata_to: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
@@ -376,14 +498,77 @@ pub fn recover_main_tokens(ctx: Context<RecoverMainTokens>) -> Result<()> {
     let global_state_seed: &[&[&[u8]]] = &[&[&GLOBAL_STATE_SEED, &[bump]]];
  
     let cpi_program = ctx.accounts.token_program.to_account_info();
-    let cpi_accounts = Transfer {
+    let cpi_accounts = TransferChecked {
         from: ctx.accounts.lfg_vault.to_account_info(),
+        mint: ctx.accounts.lfg_token_mint.to_account_info(),
         to: ctx.accounts.ata_to.to_account_info(),
         authority: ctx.accounts.global_state.to_account_info(),
     };
     let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
 
-    token::transfer(cpi_ctx.with_signer(global_state_seed), balance as u64)?;
+    token_interface::transfer_checked(cpi_ctx.with_signer(global_state_seed), balance, ctx.accounts.lfg_token_mint.decimals)?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct RecoverWsolTokens<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_STATE_SEED],
+        bump,
+        has_one = authority
+    )]
+    pub global_state: Account<'info, GlobalState>,
+
+    #[account(address = native_mint::ID)]
+    pub wsol_token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        token::mint = wsol_token_mint,
+        token::authority = global_state,
+        token::token_program = token_program,
+        seeds = [VAULT_SEED, wsol_token_mint.key().as_ref()],
+        bump,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = wsol_token_mint,
+        associated_token::authority = authority,
+        associated_token::token_program = token_program,
+    )]
+    pub ata_to: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+/// Moves the bond proceeds collected in the wSOL vault to the authority's
+/// wSOL account, where they can be unwrapped.
+pub fn recover_wsol_tokens(ctx: Context<RecoverWsolTokens>) -> Result<()> {
+    let balance = ctx.accounts.wsol_vault.amount;
+
+    let bump = ctx.bumps.global_state;
+    let global_state_seed: &[&[&[u8]]] = &[&[GLOBAL_STATE_SEED, &[bump]]];
+
+    let cpi_program = ctx.accounts.token_program.to_account_info();
+    let cpi_accounts = TransferChecked {
+        from: ctx.accounts.wsol_vault.to_account_info(),
+        mint: ctx.accounts.wsol_token_mint.to_account_info(),
+        to: ctx.accounts.ata_to.to_account_info(),
+        authority: ctx.accounts.global_state.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+    token_interface::transfer_checked(cpi_ctx.with_signer(global_state_seed), balance, ctx.accounts.wsol_token_mint.decimals)?;
 
     Ok(())
 }
@@ -401,37 +586,47 @@ pub struct Bond<'info> {
     pub global_state: Account<'info, GlobalState>,
 
     #[account(
-        mut,
+        init_if_needed,
         seeds = [USER_STATE_SEED, user.key().as_ref()],
         bump,
+        payer = user,
+        space = 8 + size_of::<UserState>()
     )]
     pub user_state: Account<'info, UserState>,
 
-    pub usdc_token_mint: Box<Account<'info, Mint>>,
-    
-    pub wsol_token_mint: Box<Account<'info, Mint>>,
-
     #[account(
         mut,
-        token::mint = usdc_token_mint,
-        token::authority = global_state,
+        seeds = [REFERRER_STATE_SEED, referrer_state.referrer.as_ref()],
+        bump,
     )]
-    pub usdc_vault: Box<Account<'info, TokenAccount>>, 
+    pub referrer_state: Option<Account<'info, ReferrerState>>,
+
+    /// Bonds are paid through a Raydium AMM v4 swap, which only moves legacy
+    /// SPL Token accounts, so Token-2022 payment mints are rejected here.
+    #[account(mint::token_program = token_program)]
+    pub usdc_token_mint: Box<InterfaceAccount<'info, Mint>>,
+    
+    #[account(address = native_mint::ID)]
+    pub wsol_token_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut,
         associated_token::mint = usdc_token_mint,
         associated_token::authority = user,
+        associated_token::token_program = token_program,
     )]
-    pub ata_user: Box<Account<'info, TokenAccount>>,
+    pub ata_user: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         init_if_needed,
         payer = user,
         token::mint = wsol_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
+        seeds = [VAULT_SEED, wsol_token_mint.key().as_ref()],
+        bump,
     )]
-    pub wsol_vault: Box<Account<'info, TokenAccount>>, 
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>, 
 
     // Raydium Swap Accounts
     /// CHECK: Safe. amm program
@@ -484,14 +679,16 @@ pub struct Bond<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 
+/// Bonds `amount` USDC by swapping it from the user's account straight into
+/// the wSOL vault, then credits LFG for the wSOL actually received. A failed
+/// swap fails the bond instead of taking the USDC for nothing.
 pub fn bond<'info>(ctx: Context<'_, '_, '_, '_, Bond<'info>>, amount: u64) -> Result<()> {
     let accts = ctx.accounts;
     
-    let user_state = &mut accts.user_state;
-    let global_state = &mut accts.global_state;
-    require!(amount <= 5_000_000_000, BondingError::AmountExceedsLimit);
-    require!(global_state.bond_open == true, BondingError::BondingClosed);
+    require!(amount <= MAX_BOND_AMOUNT, BondingError::AmountExceedsLimit);
+    require!(accts.global_state.bond_open, BondingError::BondingClosed);
     
+    let usdc_balance_before = accts.ata_user.amount;
     let wsol_balance_before = accts.wsol_vault.amount;
     
     let swap_base_in_accounts = SwapBaseIn {
@@ -508,7 +705,7 @@ pub fn bond<'info>(ctx: Context<'_, '_, '_, '_, Bond<'info>>, amount: u64) -> Re
         market_coin_vault: accts.market_coin_vault.clone(),
         market_pc_vault: accts.market_pc_vault.clone(),
         market_vault_signer: accts.market_vault_signer.clone(),
-        user_token_source: accts.usdc_vault.to_account_info(),
+        user_token_source: accts.ata_user.to_account_info(),
         user_token_destination: accts.wsol_vault.to_account_info(),
         user_source_owner: accts.user.clone(),
         token_program: accts.token_program.clone(),
@@ -519,33 +716,365 @@ pub fn bond<'info>(ctx: Context<'_, '_, '_, '_, Bond<'info>>, amount: u64) -> Re
 
     // Create a CpiContext with the specified accounts and program
     let cpi_ctx = CpiContext::new(swap_base_in_program, swap_base_in_accounts);
-    let _ = amm_anchor::swap_base_in(cpi_ctx, amount, 1);
+    amm_anchor::swap_base_in(cpi_ctx, amount, 1)?;
 
-    let wsol_amount = accts.wsol_vault.amount - wsol_balance_before;
+    accts.ata_user.reload()?;
+    accts.wsol_vault.reload()?;
+    let usdc_spent = usdc_balance_before.checked_sub(accts.ata_user.amount)
+        .ok_or(BondingError::MathError)?;
+    require!(usdc_spent <= amount, BondingError::AmountExceedsLimit);
+    let wsol_amount = accts.wsol_vault.amount.checked_sub(wsol_balance_before)
+        .ok_or(BondingError::MathError)?;
+    let amount_out = bond_amount_out(wsol_amount, accts.global_state.bond_price)?;
+    require!(amount_out > 0, BondingError::NoBond);
     
-    let amount_out = wsol_amount.checked_mul(1_000).unwrap().checked_div(global_state.bond_price).unwrap();
-    // let amount_out = amount.checked_mul(1_000).unwrap().checked_div(global_state.bond_price).unwrap();
-    let new_total_bonded = global_state.bonded_tokens.checked_add(amount_out).unwrap();
+    credit_bond(
+        &mut accts.global_state,
+        &mut accts.user_state,
+        accts.referrer_state.as_deref_mut(),
+        accts.user.key(),
+        amount_out,
+    )
+}
+
+#[derive(Accounts)]
+pub struct BondWithSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_STATE_SEED],
+        bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+
+    #[account(
+        init_if_needed,
+        seeds = [USER_STATE_SEED, user.key().as_ref()],
+        bump,
+        payer = user,
+        space = 8 + size_of::<UserState>()
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_STATE_SEED, referrer_state.referrer.as_ref()],
+        bump,
+    )]
+    pub referrer_state: Option<Account<'info, ReferrerState>>,
+
+    #[account(address = native_mint::ID)]
+    pub wsol_token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = wsol_token_mint,
+        token::authority = global_state,
+        token::token_program = token_program,
+        seeds = [VAULT_SEED, wsol_token_mint.key().as_ref()],
+        bump,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+pub fn bond_with_sol(ctx: Context<BondWithSol>, lamports: u64) -> Result<()> {
+    let accts = ctx.accounts;
+
+    require!(lamports <= MAX_BOND_LAMPORTS, BondingError::AmountExceedsLimit);
+    require!(accts.global_state.bond_open, BondingError::BondingClosed);
+
+    let wsol_balance_before = accts.wsol_vault.amount;
+
+    let cpi_ctx_transfer = CpiContext::new(
+        accts.system_program.to_account_info(),
+        system_program::Transfer {
+            from: accts.user.to_account_info(),
+            to: accts.wsol_vault.to_account_info(),
+        },
+    );
+    system_program::transfer(cpi_ctx_transfer, lamports)?;
+
+    let cpi_ctx_sync = CpiContext::new(
+        accts.token_program.to_account_info(),
+        SyncNative {
+            account: accts.wsol_vault.to_account_info(),
+        },
+    );
+    token_interface::sync_native(cpi_ctx_sync)?;
+
+    accts.wsol_vault.reload()?;
+    let wsol_amount = accts.wsol_vault.amount.checked_sub(wsol_balance_before)
+        .ok_or(BondingError::MathError)?;
+    let amount_out = bond_amount_out(wsol_amount, accts.global_state.bond_price)?;
+    require!(amount_out > 0, BondingError::NoBond);
+
+    credit_bond(
+        &mut accts.global_state,
+        &mut accts.user_state,
+        accts.referrer_state.as_deref_mut(),
+        accts.user.key(),
+        amount_out,
+    )
+}
+
+#[derive(Accounts)]
+pub struct BondExactOut<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_STATE_SEED],
+        bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+
+    #[account(
+        init_if_needed,
+        seeds = [USER_STATE_SEED, user.key().as_ref()],
+        bump,
+        payer = user,
+        space = 8 + size_of::<UserState>()
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_STATE_SEED, referrer_state.referrer.as_ref()],
+        bump,
+    )]
+    pub referrer_state: Option<Account<'info, ReferrerState>>,
+
+    /// Bonds are paid through a Raydium AMM v4 swap, which only moves legacy
+    /// SPL Token accounts, so Token-2022 payment mints are rejected here.
+    #[account(mint::token_program = token_program)]
+    pub usdc_token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = native_mint::ID)]
+    pub wsol_token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = usdc_token_mint,
+        associated_token::authority = user,
+        associated_token::token_program = token_program,
+    )]
+    pub ata_user: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = wsol_token_mint,
+        token::authority = global_state,
+        token::token_program = token_program,
+        seeds = [VAULT_SEED, wsol_token_mint.key().as_ref()],
+        bump,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Raydium Swap Accounts
+    /// CHECK: Safe. amm program
+    pub amm_program: AccountInfo<'info>,
+    /// CHECK: Safe. amm Account
+    #[account(mut)]
+    pub amm: AccountInfo<'info>,
+    /// CHECK: Safe. Amm authority Account
+    #[account(
+        seeds = [b"amm authority"],
+        bump,
+    )]
+    pub amm_authority: AccountInfo<'info>,
+    /// CHECK: Safe. amm open_orders Account
+    #[account(mut)]
+    pub amm_open_orders: AccountInfo<'info>,
+    /// CHECK: Safe. amm_coin_vault Amm Account to swap FROM or To,
+    #[account(mut)]
+    pub amm_coin_vault: AccountInfo<'info>,
+    /// CHECK: Safe. amm_pc_vault Amm Account to swap FROM or To,
+    #[account(mut)]
+    pub amm_pc_vault: AccountInfo<'info>,
+    /// CHECK: Safe.OpenBook program id
+    pub market_program: AccountInfo<'info>,
+    /// CHECK: Safe. OpenBook market Account. OpenBook program is the owner.
+    #[account(mut)]
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe. bids Account
+    #[account(mut)]
+    pub market_bids: AccountInfo<'info>,
+    /// CHECK: Safe. asks Account
+    #[account(mut)]
+    pub market_asks: AccountInfo<'info>,
+    /// CHECK: Safe. event_q Account
+    #[account(mut)]
+    pub market_event_queue: AccountInfo<'info>,
+    /// CHECK: Safe. coin_vault Account
+    #[account(mut)]
+    pub market_coin_vault: AccountInfo<'info>,
+    /// CHECK: Safe. pc_vault Account
+    #[account(mut)]
+    pub market_pc_vault: AccountInfo<'info>,
+    /// CHECK: Safe. vault_signer Account
+    #[account(mut)]
+    pub market_vault_signer: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+/// Bonds exactly `amount_out` LFG by swapping the user's USDC straight into
+/// the wSOL vault with `swap_base_out`. The AMM only debits the USDC the swap
+/// needs, so whatever is left of `max_amount_in` stays with the user.
+pub fn bond_exact_out<'info>(
+    ctx: Context<'_, '_, '_, '_, BondExactOut<'info>>,
+    amount_out: u64,
+    max_amount_in: u64,
+) -> Result<()> {
+    let accts = ctx.accounts;
+
+    require!(max_amount_in <= MAX_BOND_AMOUNT, BondingError::AmountExceedsLimit);
+    require!(accts.global_state.bond_open, BondingError::BondingClosed);
+
+    let new_total_bonded = accts.global_state.bonded_tokens.checked_add(amount_out)
+        .ok_or(BondingError::MathError)?;
+    require!(new_total_bonded <= accts.global_state.bond_cap, BondingError::OverBondCap);
+
+    let wsol_amount = bond_amount_in(amount_out, accts.global_state.bond_price)?;
+    require!(wsol_amount > 0, BondingError::NoBond);
+
+    let usdc_balance_before = accts.ata_user.amount;
+    let wsol_balance_before = accts.wsol_vault.amount;
+
+    let swap_base_out_accounts = SwapBaseOut {
+        amm: accts.amm.clone(),
+        amm_authority: accts.amm_authority.clone(),
+        amm_open_orders: accts.amm_open_orders.clone(),
+        amm_coin_vault: accts.amm_coin_vault.clone(),
+        amm_pc_vault: accts.amm_pc_vault.clone(),
+        market_program: accts.market_program.clone(),
+        market: accts.market.clone(),
+        market_bids: accts.market_bids.clone(),
+        market_asks: accts.market_asks.clone(),
+        market_event_queue: accts.market_event_queue.clone(),
+        market_coin_vault: accts.market_coin_vault.clone(),
+        market_pc_vault: accts.market_pc_vault.clone(),
+        market_vault_signer: accts.market_vault_signer.clone(),
+        user_token_source: accts.ata_user.to_account_info(),
+        user_token_destination: accts.wsol_vault.to_account_info(),
+        user_source_owner: accts.user.clone(),
+        token_program: accts.token_program.clone(),
+    };
+    let cpi_ctx = CpiContext::new(accts.amm_program.clone(), swap_base_out_accounts);
+    amm_anchor::swap_base_out(cpi_ctx, max_amount_in, wsol_amount)?;
+
+    accts.ata_user.reload()?;
+    accts.wsol_vault.reload()?;
+    let usdc_spent = usdc_balance_before.checked_sub(accts.ata_user.amount)
+        .ok_or(BondingError::MathError)?;
+    let wsol_received = accts.wsol_vault.amount.checked_sub(wsol_balance_before)
+        .ok_or(BondingError::MathError)?;
+    require!(usdc_spent <= max_amount_in, BondingError::AmountExceedsLimit);
+    require!(wsol_received >= wsol_amount, BondingError::SlippageExceeded);
+
+    credit_bond(
+        &mut accts.global_state,
+        &mut accts.user_state,
+        accts.referrer_state.as_deref_mut(),
+        accts.user.key(),
+        amount_out,
+    )
+}
+
+/// LFG bonded for `wsol_amount` at `bond_price` (wSOL per 1_000 LFG).
+fn bond_amount_out(wsol_amount: u64, bond_price: u64) -> Result<u64> {
+    Ok(wsol_amount
+        .checked_mul(1_000)
+        .ok_or(BondingError::MathError)?
+        .checked_div(bond_price)
+        .ok_or(BondingError::MathError)?)
+}
+
+/// wSOL needed to bond exactly `amount_out` LFG at `bond_price`, rounded up.
+fn bond_amount_in(amount_out: u64, bond_price: u64) -> Result<u64> {
+    let amount_in = (amount_out as u128)
+        .checked_mul(bond_price as u128)
+        .ok_or(BondingError::MathError)?
+        .checked_add(999)
+        .ok_or(BondingError::MathError)?
+        .checked_div(1_000)
+        .ok_or(BondingError::MathError)?;
+
+    u64::try_from(amount_in).map_err(|_| error!(BondingError::MathError))
+}
+
+/// Credits `amount_out` LFG to the user's vesting position, plus the referral
+/// bonus when a referrer is given.
+fn credit_bond(
+    global_state: &mut GlobalState,
+    user_state: &mut UserState,
+    referrer_state: Option<&mut ReferrerState>,
+    user: Pubkey,
+    amount_out: u64,
+) -> Result<()> {
+    if user_state.user == Pubkey::default() {
+        user_state.user = user;
+    }
+    require!(user_state.user == user, BondingError::InvalidUserState);
+
+    let new_total_bonded = global_state.bonded_tokens.checked_add(amount_out)
+        .ok_or(BondingError::MathError)?;
     require!(new_total_bonded <= global_state.bond_cap, BondingError::OverBondCap);
     
-    user_state.total_bonded += amount_out;
-    global_state.bonded_tokens += amount_out;
-    user_state.final_interaction_block = Clock::get().unwrap().unix_timestamp as u64;
+    user_state.total_bonded = user_state.total_bonded.checked_add(amount_out)
+        .ok_or(BondingError::MathError)?;
+    global_state.bonded_tokens = new_total_bonded;
+    user_state.final_interaction_block = Clock::get()?.unix_timestamp as u64;
     user_state.vest_time = global_state.vesting_time;
-    
-    let cpi_program = accts.token_program.to_account_info();
-    let cpi_accounts_transfer = Transfer {
-        from: accts.ata_user.to_account_info(),
-        to: accts.usdc_vault.to_account_info(),
-        authority: accts.user.to_account_info(),
-    };
-    
-    let cpi_ctx_transfer = CpiContext::new(cpi_program.clone(), cpi_accounts_transfer);
-    token::transfer(cpi_ctx_transfer, amount)?;
-    
+
+    if let Some(referrer_state) = referrer_state {
+        require!(referrer_state.referrer != user, BondingError::SelfReferral);
+
+        let referral_bonus = referral_bonus(
+            amount_out,
+            global_state.referral_bps,
+            global_state.referral_cap,
+            global_state.referral_paid,
+        )?;
+
+        if referral_bonus > 0 {
+            referrer_state.total_bonded = referrer_state.total_bonded.checked_add(referral_bonus)
+                .ok_or(BondingError::MathError)?;
+            referrer_state.final_interaction_block = user_state.final_interaction_block;
+            referrer_state.vest_time = global_state.vesting_time;
+            global_state.referral_paid = global_state.referral_paid.checked_add(referral_bonus)
+                .ok_or(BondingError::MathError)?;
+        }
+    }
+
     Ok(())
 }
 
+/// Referral bonus on `amount_out` at `referral_bps`, limited to what is left
+/// of the `referral_cap` budget after `referral_paid`.
+fn referral_bonus(amount_out: u64, referral_bps: u64, referral_cap: u64, referral_paid: u64) -> Result<u64> {
+    let referral_left = referral_cap.saturating_sub(referral_paid);
+
+    Ok(amount_out
+        .checked_mul(referral_bps)
+        .ok_or(BondingError::MathError)?
+        .checked_div(10_000)
+        .ok_or(BondingError::MathError)?
+        .min(referral_left))
+}
+
 #[derive(Accounts)]
 pub struct Claim<'info> {
     #[account(mut)]
@@ -567,24 +1096,26 @@ pub struct Claim<'info> {
     pub user_state: Account<'info, UserState>,
 
     #[account(mut)]
-    pub lfg_token_mint: Box<Account<'info, Mint>>,
+    pub lfg_token_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut,
         token::mint = lfg_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
     )]
-    pub ata_vault: Box<Account<'info, TokenAccount>>,
+    pub ata_vault: Box<InterfaceAccount<'info, TokenAccount>>,
     
     #[account(
         init_if_needed,
         payer = user,
         associated_token::mint = lfg_token_mint,
         associated_token::authority = user,
+        associated_token::token_program = token_program,
     )]
-    pub ata_to: Box<Account<'info, TokenAccount>>,
+    pub ata_to: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
@@ -593,26 +1124,13 @@ pub struct Claim<'info> {
 pub fn claim(ctx: Context<Claim>) -> Result<()> {
     let user_state = &mut ctx.accounts.user_state;
     let now = Clock::get()?.unix_timestamp as u64;
-    let duration_passed = now.checked_sub(user_state.final_interaction_block)
-        .ok_or(BondingError::MathError)?;
-
-    let can_claim = if duration_passed >= user_state.vest_time {
-        user_state.total_bonded
-            .checked_mul(ctx.accounts.global_state.rebase_ratio)
-            .ok_or(BondingError::MathError)?
-            .checked_div(100)
-            .ok_or(BondingError::MathError)?
-    } else {
-        user_state.total_bonded
-            .checked_mul(ctx.accounts.global_state.rebase_ratio)
-            .ok_or(BondingError::MathError)?
-            .checked_div(100)
-            .ok_or(BondingError::MathError)?
-            .checked_mul(duration_passed)
-            .ok_or(BondingError::MathError)?
-            .checked_div(user_state.vest_time)
-            .ok_or(BondingError::MathError)?
-    };
+    let can_claim = vested_amount(
+        user_state.total_bonded,
+        ctx.accounts.global_state.rebase_ratio,
+        user_state.final_interaction_block,
+        user_state.vest_time,
+        now,
+    )?;
 
     require!(can_claim > 0, BondingError::NoBond);
 
@@ -625,18 +1143,129 @@ pub fn claim(ctx: Context<Claim>) -> Result<()> {
 
     let cpi_ctx = CpiContext::new(
         ctx.accounts.token_program.to_account_info(),
-        Transfer {
+        TransferChecked {
+            from: ctx.accounts.ata_vault.to_account_info(),
+            mint: ctx.accounts.lfg_token_mint.to_account_info(),
+            to: ctx.accounts.ata_to.to_account_info(),
+            authority: ctx.accounts.global_state.to_account_info(),
+        },
+    );
+
+    token_interface::transfer_checked(cpi_ctx.with_signer(global_state_seed), can_claim, ctx.accounts.lfg_token_mint.decimals)?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferral<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_STATE_SEED],
+        bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_STATE_SEED, referrer.key().as_ref()],
+        bump,
+        has_one = referrer
+    )]
+    pub referrer_state: Account<'info, ReferrerState>,
+
+    #[account(mut)]
+    pub lfg_token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        token::mint = lfg_token_mint,
+        token::authority = global_state,
+        token::token_program = token_program,
+    )]
+    pub ata_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(
+        init_if_needed,
+        payer = referrer,
+        associated_token::mint = lfg_token_mint,
+        associated_token::authority = referrer,
+        associated_token::token_program = token_program,
+    )]
+    pub ata_to: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+pub fn claim_referral(ctx: Context<ClaimReferral>) -> Result<()> {
+    let referrer_state = &mut ctx.accounts.referrer_state;
+    let now = Clock::get()?.unix_timestamp as u64;
+    let can_claim = vested_amount(
+        referrer_state.total_bonded,
+        ctx.accounts.global_state.rebase_ratio,
+        referrer_state.final_interaction_block,
+        referrer_state.vest_time,
+        now,
+    )?;
+
+    require!(can_claim > 0, BondingError::NoBond);
+
+    referrer_state.total_bonded = referrer_state.total_bonded.checked_sub(can_claim)
+        .ok_or(BondingError::MathError)?;
+    referrer_state.final_interaction_block = now;
+
+    let bump = ctx.bumps.global_state;    
+    let global_state_seed: &[&[&[u8]]] = &[&[GLOBAL_STATE_SEED, &[bump]]];
+
+    let cpi_ctx = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
             from: ctx.accounts.ata_vault.to_account_info(),
+            mint: ctx.accounts.lfg_token_mint.to_account_info(),
             to: ctx.accounts.ata_to.to_account_info(),
             authority: ctx.accounts.global_state.to_account_info(),
         },
     );
 
-    token::transfer(cpi_ctx.with_signer(global_state_seed), can_claim as u64)?;
+    token_interface::transfer_checked(cpi_ctx.with_signer(global_state_seed), can_claim, ctx.accounts.lfg_token_mint.decimals)?;
 
     Ok(())
 }
 
+/// Portion of `total_bonded` that has vested since `final_interaction_block`,
+/// scaled by `rebase_ratio` percent.
+fn vested_amount(
+    total_bonded: u64,
+    rebase_ratio: u64,
+    final_interaction_block: u64,
+    vest_time: u64,
+    now: u64,
+) -> Result<u64> {
+    let duration_passed = now.checked_sub(final_interaction_block)
+        .ok_or(BondingError::MathError)?;
+
+    let rebased = total_bonded
+        .checked_mul(rebase_ratio)
+        .ok_or(BondingError::MathError)?
+        .checked_div(100)
+        .ok_or(BondingError::MathError)?;
+
+    if duration_passed >= vest_time {
+        return Ok(rebased);
+    }
+
+    Ok(rebased
+        .checked_mul(duration_passed)
+        .ok_or(BondingError::MathError)?
+        .checked_div(vest_time)
+        .ok_or(BondingError::MathError)?)
+}
+
 #[derive(Accounts)]
 pub struct Fund<'info> {
     #[account(mut)]
@@ -650,38 +1279,104 @@ pub struct Fund<'info> {
     pub global_state: Account<'info, GlobalState>,
 
     #[account(mut)]
-    pub lfg_token_mint: Box<Account<'info, Mint>>,
+    pub lfg_token_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut,
         token::mint = lfg_token_mint,
         token::authority = global_state,
+        token::token_program = token_program,
     )]
-    pub ata_vault: Box<Account<'info, TokenAccount>>, 
+    pub ata_vault: Box<InterfaceAccount<'info, TokenAccount>>, 
 
     #[account(
         init_if_needed,
         payer = authority,
         associated_token::mint = lfg_token_mint,
         associated_token::authority = authority,
+        associated_token::token_program = token_program,
     )]
-    pub ata_user: Box<Account<'info, TokenAccount>>,
+    pub ata_user: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
 
 pub fn fund_token(ctx: Context<Fund>, amount: u64) -> Result<()> {
-    let cpi_accounts = Transfer {
+    let cpi_accounts = TransferChecked {
         from: ctx.accounts.ata_user.to_account_info(),
+        mint: ctx.accounts.lfg_token_mint.to_account_info(),
         to: ctx.accounts.ata_vault.to_account_info(),
         authority: ctx.accounts.authority.to_account_info(),
     };
     let cpi_program = ctx.accounts.token_program.to_account_info();
     let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-    token::transfer(cpi_ctx, amount)?;
+    token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.lfg_token_mint.decimals)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bond_amount_out_rounds_down() {
+        // 1_999 wSOL at 2_000 per 1_000 LFG is just short of 1_000 LFG.
+        assert_eq!(bond_amount_out(1_999, 2_000).unwrap(), 999);
+        assert_eq!(bond_amount_out(2_000, 2_000).unwrap(), 1_000);
+        assert_eq!(bond_amount_out(1, 2_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn bond_amount_in_rounds_up() {
+        assert_eq!(bond_amount_in(1_000, 2_000).unwrap(), 2_000);
+        assert_eq!(bond_amount_in(1, 2_000).unwrap(), 2);
+        assert_eq!(bond_amount_in(1, 1).unwrap(), 1);
+        assert_eq!(bond_amount_in(0, 2_000).unwrap(), 0);
+
+        // Paying the rounded-up input always buys at least the requested output.
+        for (amount_out, bond_price) in [(1, 3), (7, 1_001), (123_456, 999), (5, 1)] {
+            let amount_in = bond_amount_in(amount_out, bond_price).unwrap();
+            assert!(bond_amount_out(amount_in, bond_price).unwrap() >= amount_out);
+        }
+    }
+
+    #[test]
+    fn bond_amounts_reject_bad_inputs() {
+        assert_eq!(bond_amount_out(1, 0).unwrap_err(), BondingError::MathError.into());
+        assert_eq!(bond_amount_out(u64::MAX, 1).unwrap_err(), BondingError::MathError.into());
+        assert_eq!(bond_amount_in(u64::MAX, u64::MAX).unwrap_err(), BondingError::MathError.into());
+    }
+
+    #[test]
+    fn vested_amount_is_linear_until_vest_time() {
+        // 50% rebase of 1_000 bonded, vesting over 100 seconds from t = 10.
+        assert_eq!(vested_amount(1_000, 50, 10, 100, 10).unwrap(), 0);
+        assert_eq!(vested_amount(1_000, 50, 10, 100, 35).unwrap(), 125);
+        assert_eq!(vested_amount(1_000, 50, 10, 100, 110).unwrap(), 500);
+        assert_eq!(vested_amount(1_000, 50, 10, 100, 1_000).unwrap(), 500);
+    }
+
+    #[test]
+    fn vested_amount_rejects_time_before_last_claim() {
+        assert_eq!(vested_amount(1_000, 50, 10, 100, 9).unwrap_err(), BondingError::MathError.into());
+        assert_eq!(vested_amount(u64::MAX, 50, 0, 100, 50).unwrap_err(), BondingError::MathError.into());
+    }
+
+    #[test]
+    fn referral_bonus_is_capped_by_the_budget_left() {
+        // 5% of 10_000 with plenty of budget.
+        assert_eq!(referral_bonus(10_000, 500, 1_000_000, 0).unwrap(), 500);
+        // Only 200 of the budget is left.
+        assert_eq!(referral_bonus(10_000, 500, 1_000, 800).unwrap(), 200);
+        // Exhausted, or overpaid after the cap was lowered.
+        assert_eq!(referral_bonus(10_000, 500, 1_000, 1_000).unwrap(), 0);
+        assert_eq!(referral_bonus(10_000, 500, 1_000, 2_000).unwrap(), 0);
+        // Referrals switched off.
+        assert_eq!(referral_bonus(10_000, 0, 1_000, 0).unwrap(), 0);
+        assert_eq!(referral_bonus(u64::MAX, 2, 1_000, 0).unwrap_err(), BondingError::MathError.into());
+    }
+}
@@ -25,6 +25,10 @@ pub mod solana_lfg {
         instructions::init_user_state(ctx)
     }
 
+    pub fn init_referrer_state(ctx: Context<InitReferrerState>) -> Result<()> {
+        instructions::init_referrer_state(ctx)
+    }
+
     pub fn set_bump(ctx: Context<SetBump>, new_bump: u8) -> Result<()> {
         instructions::set_bump(ctx, new_bump)
     }
@@ -45,6 +49,14 @@ pub mod solana_lfg {
         instructions::set_vesting_time(ctx, new_vesting_time)
     }
 
+    pub fn set_referral_config(ctx: Context<SetReferralConfig>, referral_bps: u64, referral_cap: u64) -> Result<()> {
+        instructions::set_referral_config(ctx, referral_bps, referral_cap)
+    }
+
+    pub fn migrate_global_state(ctx: Context<MigrateGlobalState>) -> Result<()> {
+        instructions::migrate_global_state(ctx)
+    }
+
     pub fn set_open_bond(ctx: Context<SetOpenBond>, bond_open: bool) -> Result<()> {
         instructions::set_open_bond(ctx, bond_open)
     }
@@ -57,14 +69,30 @@ pub mod solana_lfg {
         instructions::recover_main_tokens(ctx)
     }
 
+    pub fn recover_wsol_tokens(ctx: Context<RecoverWsolTokens>) -> Result<()> {
+        instructions::recover_wsol_tokens(ctx)
+    }
+
     pub fn bond(ctx: Context<Bond>, amount: u64) -> Result<()> {
         instructions::bond(ctx, amount)
     }
 
+    pub fn bond_with_sol(ctx: Context<BondWithSol>, lamports: u64) -> Result<()> {
+        instructions::bond_with_sol(ctx, lamports)
+    }
+
+    pub fn bond_exact_out(ctx: Context<BondExactOut>, amount_out: u64, max_amount_in: u64) -> Result<()> {
+        instructions::bond_exact_out(ctx, amount_out, max_amount_in)
+    }
+
     pub fn claim(ctx: Context<Claim>) -> Result<()> {
         instructions::claim(ctx)
     }
 
+    pub fn claim_referral(ctx: Context<ClaimReferral>) -> Result<()> {
+        instructions::claim_referral(ctx)
+    }
+
     pub fn fund_token(ctx: Context<Fund>, amount: u64) -> Result<()> {
         instructions::fund_token(ctx, amount)
     }
@@ -13,6 +13,9 @@ pub struct GlobalState {
     pub bond_open: bool,
     pub rebase_ratio: u64,
     pub bump: u8,
+    pub referral_bps: u64,
+    pub referral_cap: u64,
+    pub referral_paid: u64,
 }
 
 #[account]
@@ -22,3 +25,11 @@ pub struct UserState {
     pub final_interaction_block: u64,
     pub vest_time: u64,
 }
+
+#[account]
+pub struct ReferrerState {
+    pub referrer: Pubkey,
+    pub total_bonded: u64,
+    pub final_interaction_block: u64,
+    pub vest_time: u64,
+}
\ No newline at end of file
@@ -1,13 +1,37 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
+use anchor_spl::token::{self, Burn, CloseAccount, Mint, Token, TokenAccount, Transfer};
 use anchor_spl::associated_token::{AssociatedToken};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::mem::size_of;
+use anchor_lang::solana_program::clock::DEFAULT_MS_PER_SLOT;
 
 declare_id!("BhURC7xGuDaucKUkpYhjRWktXyjYAhn6ZLCZj2KmZHAd");
 
 const ACC_PRECISION: u128 = 100_000_000_000;
+const BPS_DENOMINATOR: u64 = 10_000;
+const MAX_LOCK_DURATION: i64 = 30 * 86400;
+const MAX_EARLY_WITHDRAW_FEE_BPS: u64 = 2_000;
+const LEGACY_EARLY_WITHDRAW_FEE_BPS: u64 = 1_000;
+
+const MAX_POOL_REWARDS: usize = 3;
+const VOTER_CHECKPOINTS: usize = 32;
+const MAX_POOLS: usize = 32;
+const REGISTRY_PAGE_SIZE: usize = 12;
+
+/// Borsh size of a `VoterWeightRecord` with every optional field set.
+const VOTER_WEIGHT_RECORD_SIZE: usize = 8 + 32 * 3 + 8 + (1 + 8) + (1 + 1) + (1 + 32) + 8;
+
+/// Lock period in seconds and reward boost in basis points for each lock tier.
+const LOCK_TIERS: [(i64, u64); 5] = [
+    (0, 10_000),
+    (30 * 86400, 12_500),
+    (90 * 86400, 15_000),
+    (180 * 86400, 20_000),
+    (365 * 86400, 30_000),
+];
 
 #[program]
 pub mod lfg_staking {
@@ -23,6 +47,7 @@ pub mod lfg_staking {
         state.start_time = _ctx.accounts.clock.unix_timestamp;
         state.token_per_second = token_per_second;
         state.lfg_reward_vault = _ctx.accounts.lfg_reward_vault.key();        
+        state.reward_end_time = state.start_time;
 
         Ok(())
     }
@@ -45,9 +70,29 @@ pub mod lfg_staking {
         let cpi_program = _ctx.accounts.lfg_token_program.to_account_info();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
         token::transfer(cpi_ctx, amount)?;
+
+        let state = &mut _ctx.accounts.state;
+        let now = _ctx.accounts.clock.unix_timestamp;
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, None)?;
+        if let Some(seconds) = u128::from(amount).checked_div(state.emission_per_second()) {
+            let seconds = i64::try_from(seconds).map_err(|_| ErrorCode::MathOverflow)?;
+            state.reward_end_time = state.reward_end_time.max(now).checked_add(seconds).ok_or(ErrorCode::MathOverflow)?;
+        }
+        emit!(RewardFunded {
+            amount,
+            reward_end_time: state.reward_end_time
+        });
         Ok(())
     }
 
+    pub fn reward_runway(_ctx: Context<RewardRunway>) -> Result<i64> {
+        let state = &_ctx.accounts.state;
+        Ok(state
+            .reward_end_time
+            .saturating_sub(_ctx.accounts.clock.unix_timestamp)
+            .max(0))
+    }
+
     pub fn set_authority(ctx: Context<SetAuthority>, new_authority: Pubkey) -> Result<()> {
         let accts = ctx.accounts;
         let state = &mut accts.state;
@@ -56,56 +101,125 @@ pub mod lfg_staking {
         Ok(())
     }
 
+    /// Sets how early-withdraw fees are split between the treasury fee vault,
+    /// burning, and the stakers left in the pool the fee was charged in.
+    pub fn set_fee_split(
+        _ctx: Context<SetFeeSplit>,
+        treasury_bps: u64,
+        burn_bps: u64,
+        redistribute_bps: u64,
+    ) -> Result<()> {
+        let total_bps = treasury_bps
+            .checked_add(burn_bps)
+            .and_then(|bps| bps.checked_add(redistribute_bps));
+        require!(total_bps == Some(BPS_DENOMINATOR), ErrorCode::InvalidFeeSplit);
+
+        let state = &mut _ctx.accounts.state;
+        state.fee_treasury_bps = treasury_bps;
+        state.fee_burn_bps = burn_bps;
+        state.fee_redistribute_bps = redistribute_bps;
+        emit!(FeeSplitChanged {
+            treasury_bps,
+            burn_bps,
+            redistribute_bps
+        });
+        Ok(())
+    }
+
     pub fn change_tokens_per_second(
         _ctx: Context<ChangeTokensPerSecond>,
         token_per_second: u64,
     ) -> Result<()> {
         let state = &mut _ctx.accounts.state;
 
-        let iter = &mut _ctx.remaining_accounts.iter();
-        loop {
-            if let Ok(pool_acc_info) = next_account_info(iter) {
-                let mut pool = FarmPoolAccount::deserialize(&mut &pool_acc_info.try_borrow_data()?[8..])?;
-                pool.update(&state, &_ctx.accounts.clock)?;
-            } else {
-                break;
-            }
-        }
-        
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, None)?;
+
+        let emission_per_second = state.emission_per_second();
         state.token_per_second = token_per_second;
+        state.rescale_schedule(
+            _ctx.accounts.clock.unix_timestamp,
+            emission_per_second,
+            _ctx.accounts.lfg_reward_vault.amount,
+        )?;
         emit!(RateChanged {
             token_per_second
         });
         Ok(())
     }
 
+    /// Sets the LFG emitted per slot to pools that accrue per slot. Slot
+    /// accrual still stops at `reward_end_time`, which moves like it does for
+    /// `change_tokens_per_second`.
+    pub fn change_tokens_per_slot(
+        _ctx: Context<ChangeTokensPerSecond>,
+        token_per_slot: u64,
+    ) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, None)?;
+
+        let emission_per_second = state.emission_per_second();
+        state.token_per_slot = token_per_slot;
+        state.rescale_schedule(
+            _ctx.accounts.clock.unix_timestamp,
+            emission_per_second,
+            _ctx.accounts.lfg_reward_vault.amount,
+        )?;
+        emit!(SlotRateChanged {
+            token_per_slot
+        });
+        Ok(())
+    }
+
+    pub fn create_pool_registry(_ctx: Context<CreatePoolRegistry>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the active registry entries among slots
+    /// `start..start + REGISTRY_PAGE_SIZE`, which keeps the result within the
+    /// return data limit.
+    pub fn pool_registry(_ctx: Context<ViewPoolRegistry>, start: u8) -> Result<Vec<PoolRegistryEntry>> {
+        let start = usize::from(start).min(MAX_POOLS);
+        let end = (start + REGISTRY_PAGE_SIZE).min(MAX_POOLS);
+        Ok(_ctx.accounts.registry.entries[start..end]
+            .iter()
+            .filter(|entry| entry.active)
+            .cloned()
+            .collect())
+    }
+
     pub fn create_pool(
         _ctx: Context<CreateFarmPool>,
         point: u64,
         amount_multipler: u64,
+        lock_duration: i64,
+        early_withdraw_fee_bps: u64,
+        start_time: i64,
     ) -> Result<()> {
+        validate_pool_lock(lock_duration, early_withdraw_fee_bps)?;
+        require!(amount_multipler > 0, ErrorCode::InvalidMultiplier);
+
         let state = &mut _ctx.accounts.state;
         
-        let iter = &mut _ctx.remaining_accounts.iter();
-        loop {
-            if let Ok(pool_acc_info) = next_account_info(iter) {
-                let mut pool = FarmPoolAccount::deserialize(&mut &pool_acc_info.try_borrow_data()?[8..])?;
-                pool.update(&state, &_ctx.accounts.clock)?;
-            } else {
-                break;
-            }
-        }
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, None)?;
 
         let pool = &mut _ctx.accounts.pool;
         pool.bump = _ctx.bumps.pool;
         pool.mint = _ctx.accounts.mint.key();
         pool.vault = _ctx.accounts.vault.key();
         pool.point = point;
-        pool.lock_duration = 2 * 86400;
+        pool.lock_duration = lock_duration;
+        pool.early_withdraw_fee_bps = early_withdraw_fee_bps;
         pool.amount_multipler = amount_multipler;
         pool.authority = _ctx.accounts.authority.key();
+        pool.start_time = start_time.max(_ctx.accounts.clock.unix_timestamp);
+        pool.last_reward_time = pool.start_time;
+        pool.last_reward_slot = _ctx.accounts.clock.slot;
 
-        state.total_point = state.total_point.checked_add(point).unwrap();
+        state.total_point = state.total_point.checked_add(point).ok_or(ErrorCode::MathOverflow)?;
+        state.total_weight = state.total_weight.checked_add(pool.weight()?).ok_or(ErrorCode::MathOverflow)?;
+        state.pool_count = state.pool_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        _ctx.accounts.registry.register(pool.key(), pool.mint, point)?;
 
         emit!(PoolCreated {
             pool: _ctx.accounts.pool.key(),
@@ -114,12 +228,72 @@ pub mod lfg_staking {
         Ok(())
     }
 
+    pub fn close_pool(_ctx: Context<CloseFarmPool>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &_ctx.accounts.pool;
+
+        require!(pool.amount == 0 && pool.total_user == 0, ErrorCode::WorkingPool);
+        require!(
+            pool.rewards.iter().all(|reward| reward.mint == Pubkey::default()),
+            ErrorCode::RewardSlotInUse
+        );
+
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, Some(pool.key()))?;
+
+        state.total_point = state.total_point.checked_sub(pool.point).ok_or(ErrorCode::MathOverflow)?;
+        state.total_weight = state.total_weight.checked_sub(pool.weight()?).ok_or(ErrorCode::MathOverflow)?;
+        state.pool_count = state.pool_count.checked_sub(1).ok_or(ErrorCode::MathOverflow)?;
+        _ctx.accounts.registry.deactivate(pool.key())?;
+
+        let seeds = &[pool.mint.as_ref(), &[pool.bump]];
+        let signer = &[&seeds[..]];
+
+        // With every user gone, whatever is left in the vault is rounding dust
+        // from fee redistribution that nobody can claim.
+        let dust = _ctx.accounts.vault.amount;
+        if dust > 0 {
+            let cpi_accounts = Transfer {
+                from: _ctx.accounts.vault.to_account_info(),
+                to: _ctx.accounts.authority_vault.to_account_info(),
+                authority: _ctx.accounts.pool.to_account_info(),
+            };
+            let cpi_program = _ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, dust)?;
+        }
+
+        let cpi_accounts = CloseAccount {
+            account: _ctx.accounts.vault.to_account_info(),
+            destination: _ctx.accounts.authority.to_account_info(),
+            authority: _ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_program = _ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::close_account(cpi_ctx)?;
+
+        emit!(PoolClosed {
+            pool: _ctx.accounts.pool.key(),
+            mint: _ctx.accounts.pool.mint,
+            dust
+        });
+        Ok(())
+    }
+
+    /// Changes the pool's share of the LFG emission. Every other pool's share
+    /// changes with it, so they must all be passed in remaining accounts.
     pub fn change_pool_amount_multipler(
         _ctx: Context<ChangePoolSetting>,
         amount_multipler: u64,
     ) -> Result<()> {
+        require!(amount_multipler > 0, ErrorCode::InvalidMultiplier);
+
+        let state = &mut _ctx.accounts.state;
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, Some(_ctx.accounts.pool.key()))?;
+
         let pool = &mut _ctx.accounts.pool;
-        pool.amount_multipler = amount_multipler;
+        pool.update(state, &_ctx.accounts.clock)?;
+        let point = pool.point;
+        pool.reweight(state, point, amount_multipler)?;
         emit!(PoolAmountMultiplerChanged {
             pool: _ctx.accounts.pool.key(),
             amount_multipler
@@ -127,27 +301,55 @@ pub mod lfg_staking {
         Ok(())
     }
 
-    pub fn change_pool_point(_ctx: Context<ChangePoolSetting>, point: u64) -> Result<()> {
+    pub fn change_pool_lock(
+        _ctx: Context<ChangePoolSetting>,
+        lock_duration: i64,
+        early_withdraw_fee_bps: u64,
+    ) -> Result<()> {
+        validate_pool_lock(lock_duration, early_withdraw_fee_bps)?;
+
+        let pool = &mut _ctx.accounts.pool;
+        pool.lock_duration = lock_duration;
+        pool.early_withdraw_fee_bps = early_withdraw_fee_bps;
+        emit!(PoolLockChanged {
+            pool: _ctx.accounts.pool.key(),
+            lock_duration,
+            early_withdraw_fee_bps
+        });
+        Ok(())
+    }
+
+    /// Switches the pool between accruing LFG per second and per slot.
+    pub fn change_pool_accrual(_ctx: Context<ChangePoolSetting>, accrue_per_slot: bool) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        pool.accrue_per_slot = accrue_per_slot;
+        pool.last_reward_slot = _ctx.accounts.clock.slot;
+        emit!(PoolAccrualChanged {
+            pool: _ctx.accounts.pool.key(),
+            accrue_per_slot
+        });
+        Ok(())
+    }
+
+    pub fn change_pool_point(_ctx: Context<ChangePoolPoint>, point: u64) -> Result<()> {
         let state = &mut _ctx.accounts.state;
         
-        let iter = &mut _ctx.remaining_accounts.iter();
-        loop {
-            if let Ok(pool_acc_info) = next_account_info(iter) {
-                let mut pool = FarmPoolAccount::deserialize(&mut &pool_acc_info.try_borrow_data()?[8..])?;
-                pool.update(&state, &_ctx.accounts.clock)?;
-            } else {
-                break;
-            }
-        }
+        update_all_pools(state, &_ctx.accounts.clock, _ctx.remaining_accounts, Some(_ctx.accounts.pool.key()))?;
 
         let pool = &mut _ctx.accounts.pool;
+        pool.update(state, &_ctx.accounts.clock)?;
         state.total_point = state
             .total_point
             .checked_sub(pool.point)
-            .unwrap()
+            .ok_or(ErrorCode::MathOverflow)?
             .checked_add(point)
-            .unwrap();
-        pool.point = point;
+            .ok_or(ErrorCode::MathOverflow)?;
+        let amount_multipler = pool.amount_multipler;
+        pool.reweight(state, point, amount_multipler)?;
+        _ctx.accounts.registry.set_point(pool.key(), point)?;
         emit!(PoolPointChanged {
             pool: _ctx.accounts.pool.key(),
             point
@@ -155,8 +357,148 @@ pub mod lfg_staking {
         Ok(())
     }
 
+    pub fn add_pool_reward(
+        _ctx: Context<AddPoolReward>,
+        index: u8,
+        token_per_second: u64,
+        start_time: i64,
+        end_time: i64,
+    ) -> Result<()> {
+        require!(end_time > start_time, ErrorCode::InvalidRewardSchedule);
+
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        let reward = pool
+            .rewards
+            .get_mut(usize::from(index))
+            .ok_or(ErrorCode::InvalidRewardIndex)?;
+        require!(reward.mint == Pubkey::default(), ErrorCode::RewardSlotInUse);
+
+        // The slot keeps its accumulator, which the reward debts of the pool's
+        // users were last measured against.
+        reward.mint = _ctx.accounts.reward_mint.key();
+        reward.vault = _ctx.accounts.reward_vault.key();
+        reward.token_per_second = token_per_second;
+        reward.start_time = start_time;
+        reward.end_time = end_time;
+        let index = usize::from(index);
+        pool.reward_accrued[index] = 0;
+        pool.reward_paid[index] = 0;
+
+        emit!(PoolRewardChanged {
+            pool: _ctx.accounts.pool.key(),
+            mint: _ctx.accounts.reward_mint.key(),
+            token_per_second,
+            start_time,
+            end_time
+        });
+        Ok(())
+    }
+
+    pub fn change_pool_reward(
+        _ctx: Context<ChangePoolSetting>,
+        index: u8,
+        token_per_second: u64,
+        end_time: i64,
+    ) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        let reward = pool
+            .rewards
+            .get_mut(usize::from(index))
+            .ok_or(ErrorCode::InvalidRewardIndex)?;
+        require!(reward.mint != Pubkey::default(), ErrorCode::InvalidRewardIndex);
+        require!(end_time > reward.start_time, ErrorCode::InvalidRewardSchedule);
+
+        reward.token_per_second = token_per_second;
+        reward.end_time = end_time;
+        let reward = *reward;
+
+        emit!(PoolRewardChanged {
+            pool: _ctx.accounts.pool.key(),
+            mint: reward.mint,
+            token_per_second,
+            start_time: reward.start_time,
+            end_time
+        });
+        Ok(())
+    }
+
+    /// Sends what an ended partner reward's vault holds beyond what its users
+    /// are still owed back to the pool authority. Once nothing is owed the
+    /// vault is closed and the reward slot freed.
+    pub fn reclaim_pool_reward(_ctx: Context<ReclaimPoolReward>, index: u8) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        let index = usize::from(index);
+        let reward = *pool.rewards.get(index).ok_or(ErrorCode::InvalidRewardIndex)?;
+        require!(reward.mint != Pubkey::default(), ErrorCode::InvalidRewardIndex);
+        require!(
+            reward.end_time <= _ctx.accounts.clock.unix_timestamp,
+            ErrorCode::RewardStillRunning
+        );
+        require_keys_eq!(_ctx.accounts.reward_vault.key(), reward.vault, ErrorCode::InvalidRewardVault);
+        require_keys_eq!(_ctx.accounts.authority_vault.mint, reward.mint, ErrorCode::InvalidRewardVault);
+
+        let owed = if pool.total_user == 0 {
+            0
+        } else {
+            pool.reward_accrued[index].saturating_sub(pool.reward_paid[index])
+        };
+        let balance = _ctx.accounts.reward_vault.amount;
+        let surplus = u64::try_from(u128::from(balance).saturating_sub(owed)).map_err(|_| ErrorCode::MathOverflow)?;
+
+        let (mint, bump) = (pool.mint, pool.bump);
+        let seeds = &[mint.as_ref(), &[bump]];
+        let signer = &[&seeds[..]];
+        if surplus > 0 {
+            let cpi_accounts = Transfer {
+                from: _ctx.accounts.reward_vault.to_account_info(),
+                to: _ctx.accounts.authority_vault.to_account_info(),
+                authority: pool.to_account_info(),
+            };
+            let cpi_program = _ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, surplus)?;
+        }
+
+        let closed = owed == 0;
+        if closed {
+            let cpi_accounts = CloseAccount {
+                account: _ctx.accounts.reward_vault.to_account_info(),
+                destination: _ctx.accounts.authority.to_account_info(),
+                authority: pool.to_account_info(),
+            };
+            let cpi_program = _ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::close_account(cpi_ctx)?;
+
+            pool.rewards[index] = PoolReward {
+                acc_reward_per_share: reward.acc_reward_per_share,
+                ..Default::default()
+            };
+            pool.reward_accrued[index] = 0;
+            pool.reward_paid[index] = 0;
+        }
+
+        emit!(PoolRewardReclaimed {
+            pool: _ctx.accounts.pool.key(),
+            mint: reward.mint,
+            amount: surplus,
+            closed
+        });
+        Ok(())
+    }
+
     pub fn create_user(_ctx: Context<CreatePoolUser>) -> Result<()> {
         let user = &mut _ctx.accounts.user;
+        user.initialized = true;
         user.authority = _ctx.accounts.authority.key();
         user.bump = _ctx.bumps.user;
         user.pool = _ctx.accounts.pool.key();
@@ -171,17 +513,54 @@ pub mod lfg_staking {
         Ok(())
     }
 
-    pub fn deposit(_ctx: Context<Stake>, amount: u64) -> Result<()> {
+    /// Closes an emptied position and returns its rent to the owner. Everything
+    /// owed to it must have been withdrawn, harvested and claimed first.
+    pub fn close_user(_ctx: Context<ClosePoolUser>) -> Result<()> {
+        let user = &_ctx.accounts.user;
+        require!(
+            user.amount == 0
+                && user.boosted_amount == 0
+                && user.lfg_reward_amount == 0
+                && user.lfg_extra_reward == 0
+                && user.fee_reward_amount == 0
+                && user.reward_amounts.iter().all(|amount| *amount == 0),
+            ErrorCode::PositionNotEmpty
+        );
+
+        let pool = &mut _ctx.accounts.pool;
+        pool.total_user = pool.total_user.checked_sub(1).ok_or(ErrorCode::MathOverflow)?;
+        emit!(UserClosed {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.authority.key(),
+        });
+        Ok(())
+    }
+
+    pub fn deposit(_ctx: Context<Stake>, amount: u64, lock_tier: u8) -> Result<()> {
         let state = &mut _ctx.accounts.state;
         let user = &mut _ctx.accounts.user;
         let pool = &mut _ctx.accounts.pool;
+        let now = _ctx.accounts.clock.unix_timestamp;
 
-
-        pool.update(&state, &_ctx.accounts.clock)?;
+        pool.update(state, &_ctx.accounts.clock)?;
         user.calculate_lfg_reward_amount(&pool)?;
 
-        user.amount = user.amount.checked_add(amount).unwrap();
-        pool.amount = pool.amount.checked_add(amount).unwrap();
+        user.lock(lock_tier, now)?;
+        user.amount = user.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        pool.amount = pool.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        user.update_boosted_amount(pool, now)?;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                true,
+            )?;
+        }
 
         user.calculate_lfg_reward_debt(&pool)?;
         user.last_stake_time = _ctx.accounts.clock.unix_timestamp;
@@ -198,6 +577,74 @@ pub mod lfg_staking {
             pool: _ctx.accounts.pool.key(),
             user: _ctx.accounts.user.key(),
             authority: _ctx.accounts.authority.key(),
+            amount,
+            lock_tier,
+            unlock_time: _ctx.accounts.user.unlock_time
+        });
+        Ok(())
+    }
+
+    /// Stakes the payer's tokens into the beneficiary's position, creating it
+    /// if needed. Only the beneficiary can withdraw. Third-party deposits keep
+    /// the position's lock as it is and move its early-withdraw fee window
+    /// only in proportion to the amount added, so they cannot be used to trap
+    /// someone else's stake or to skip the fee.
+    pub fn deposit_for(_ctx: Context<DepositFor>, amount: u64) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let user = &mut _ctx.accounts.user;
+        let pool = &mut _ctx.accounts.pool;
+        let beneficiary = _ctx.accounts.beneficiary.key();
+        let now = _ctx.accounts.clock.unix_timestamp;
+        require!(amount > 0, ErrorCode::ZeroAmount);
+        require_keys_neq!(beneficiary, Pubkey::default(), ErrorCode::InvalidBeneficiary);
+
+        if !user.initialized {
+            user.initialized = true;
+            user.authority = beneficiary;
+            user.bump = _ctx.bumps.user;
+            user.pool = pool.key();
+            pool.total_user = pool.total_user.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+            emit!(UserCreated {
+                pool: pool.key(),
+                user: user.key(),
+                authority: beneficiary,
+            });
+        }
+
+        pool.update(state, &_ctx.accounts.clock)?;
+        user.calculate_lfg_reward_amount(pool)?;
+
+        user.blend_stake_time(amount, now)?;
+        user.amount = user.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        pool.amount = pool.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        user.update_boosted_amount(pool, now)?;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                true,
+            )?;
+        }
+
+        user.calculate_lfg_reward_debt(pool)?;
+
+        let cpi_accounts = Transfer {
+            from: _ctx.accounts.payer_vault.to_account_info(),
+            to: _ctx.accounts.pool_vault.to_account_info(),
+            authority: _ctx.accounts.payer.to_account_info(),
+        };
+        let cpi_program = _ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+        emit!(DepositedFor {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            payer: _ctx.accounts.payer.key(),
+            beneficiary,
             amount
         });
         Ok(())
@@ -209,23 +656,45 @@ pub mod lfg_staking {
         let pool = &mut _ctx.accounts.pool;
 
         require!(user.amount >= amount, ErrorCode::UnstakeOverAmount);
+        require!(
+            user.unlock_time <= _ctx.accounts.clock.unix_timestamp,
+            ErrorCode::StillLocked
+        );
         let mut fee = 0;
         if user.last_stake_time
                 .checked_add(pool.lock_duration)
-                .unwrap()
+                .ok_or(ErrorCode::MathOverflow)?
                 > _ctx.accounts.clock.unix_timestamp
         {
-            fee = amount.checked_mul(10).unwrap().checked_div(100).unwrap();
+            fee = amount
+                .checked_mul(pool.early_withdraw_fee_bps)
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(ErrorCode::DivideByZero)?;
         }
         let amount_out = amount - fee;
 
-        pool.update(&state, &_ctx.accounts.clock)?;
+        pool.update(state, &_ctx.accounts.clock)?;
 
         user.calculate_lfg_reward_amount(&pool)?;
 
         user.last_stake_time = _ctx.accounts.clock.unix_timestamp;
-        user.amount = user.amount.checked_sub(amount).unwrap();
-        pool.amount = pool.amount.checked_sub(amount).unwrap();
+        user.amount = user.amount.checked_sub(amount).ok_or(ErrorCode::MathOverflow)?;
+        pool.amount = pool.amount.checked_sub(amount).ok_or(ErrorCode::MathOverflow)?;
+        user.update_boosted_amount(pool, _ctx.accounts.clock.unix_timestamp)?;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                false,
+            )?;
+        }
+        // Redistribute after the user's stake is gone so they get none of their own fee.
+        let fee_shares = split_fee(state, pool, fee)?;
 
         user.calculate_lfg_reward_debt(&pool)?;
         drop(pool);
@@ -236,12 +705,6 @@ pub mod lfg_staking {
             authority: _ctx.accounts.pool.to_account_info(),
         };
 
-        let cpi_fee_accounts = Transfer {
-            from: _ctx.accounts.pool_vault.to_account_info(),
-            to: _ctx.accounts.fee_vault.to_account_info(),
-            authority: _ctx.accounts.pool.to_account_info(),
-        };
-
         let new_pool = &_ctx.accounts.pool;
         let seeds = &[new_pool.mint.as_ref(), &[new_pool.bump]];
         let signer = &[&seeds[..]];
@@ -249,9 +712,7 @@ pub mod lfg_staking {
         let cpi_program = _ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
         token::transfer(cpi_ctx, amount_out)?;
-        let cpi_fee_program = _ctx.accounts.token_program.to_account_info();
-        let cpi_fee_ctx = CpiContext::new_with_signer(cpi_fee_program, cpi_fee_accounts, signer);
-        token::transfer(cpi_fee_ctx, fee)?;
+        collect_fee(_ctx.accounts, fee_shares)?;
         emit!(Withdraw {
             pool: _ctx.accounts.pool.key(),
             user: _ctx.accounts.user.key(),
@@ -261,32 +722,111 @@ pub mod lfg_staking {
         Ok(())
     }
 
-    pub fn harvest(_ctx: Context<Harvest>) -> Result<()> {
+    /// Returns the whole stake without touching the reward math, forfeiting
+    /// everything the user has accrued. A lock tier still binds, and the
+    /// early-withdraw fee still applies inside the pool's lock duration.
+    pub fn emergency_withdraw(_ctx: Context<Stake>) -> Result<()> {
         let state = &mut _ctx.accounts.state;
-        let pool = &mut _ctx.accounts.pool;
         let user = &mut _ctx.accounts.user;
+        let pool = &mut _ctx.accounts.pool;
+        let now = _ctx.accounts.clock.unix_timestamp;
 
-        pool.update(&state, &_ctx.accounts.clock)?;
+        let amount = user.amount;
+        require!(amount > 0, ErrorCode::UnstakeOverAmount);
+        require!(user.unlock_time <= now, ErrorCode::StillLocked);
 
-        user.calculate_lfg_reward_amount(&pool)?;
+        let mut fee = 0;
+        if user.last_stake_time.saturating_add(pool.lock_duration) > now {
+            fee = u64::try_from(
+                u128::from(amount) * u128::from(pool.early_withdraw_fee_bps)
+                    / u128::from(BPS_DENOMINATOR),
+            )
+            .map_err(|_| ErrorCode::MathOverflow)?;
+        }
+        let amount_out = amount - fee;
 
-        let total_lfg_reward = user.lfg_reward_amount.checked_add(user.lfg_extra_reward).unwrap().try_into().unwrap();
+        // Forfeited rewards will never be paid, so they are released from the
+        // LFG reserve and stop counting as owed when reward vaults are reclaimed.
+        state.lfg_accrued = state.lfg_accrued.saturating_sub(user.unsettled_lfg(pool));
+        for (index, forfeited) in user.unsettled_rewards(pool).iter().enumerate() {
+            pool.reward_paid[index] = pool.reward_paid[index].saturating_add(*forfeited);
+        }
+        pool.amount = pool.amount.saturating_sub(amount);
+        pool.boosted_amount = pool.boosted_amount.saturating_sub(user.boosted_amount);
 
-        let cpi_lfg_accounts = Transfer {
-            from: _ctx.accounts.lfg_reward_vault.to_account_info(),
-            to: _ctx.accounts.lfg_user_vault.to_account_info(),
-            authority: state.to_account_info(),
-        };
+        user.amount = 0;
+        user.boosted_amount = 0;
+        user.lock_tier = 0;
+        user.unlock_time = 0;
+        user.lfg_reward_amount = 0;
+        user.lfg_extra_reward = 0;
+        user.lfg_reward_debt = 0;
+        user.reward_amounts = [0; MAX_POOL_REWARDS];
+        user.reward_debts = [0; MAX_POOL_REWARDS];
+        user.fee_reward_amount = 0;
+        user.fee_reward_debt = 0;
+        user.last_stake_time = now;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                false,
+            )?;
+        }
 
-        let seeds = &[b"state".as_ref(), &[state.bump]];
+        let fee_shares = split_fee(state, pool, fee)?;
+
+        let seeds = &[pool.mint.as_ref(), &[pool.bump]];
         let signer = &[&seeds[..]];
-        let cpi_lfg_program = _ctx.accounts.lfg_token_program.to_account_info();
-        let cpi_lfg_ctx = CpiContext::new_with_signer(cpi_lfg_program, cpi_lfg_accounts, signer);
 
-        token::transfer(cpi_lfg_ctx, total_lfg_reward)?;
+        let cpi_accounts = Transfer {
+            from: _ctx.accounts.pool_vault.to_account_info(),
+            to: _ctx.accounts.user_vault.to_account_info(),
+            authority: pool.to_account_info(),
+        };
+        let cpi_program = _ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount_out)?;
+
+        collect_fee(_ctx.accounts, fee_shares)?;
 
-        user.lfg_reward_amount = 0;
-        user.lfg_extra_reward = 0;
+        emit!(EmergencyWithdraw {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.authority.key(),
+            amount,
+            fee
+        });
+        Ok(())
+    }
+
+    pub fn harvest<'info>(_ctx: Context<'_, '_, '_, 'info, Harvest<'info>>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        let user = &mut _ctx.accounts.user;
+
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        user.calculate_lfg_reward_amount(&pool)?;
+
+        let total_lfg_reward = pay_lfg_reward(
+            state,
+            user,
+            &_ctx.accounts.lfg_reward_vault,
+            _ctx.accounts.lfg_user_vault.to_account_info(),
+            _ctx.accounts.lfg_token_program.to_account_info(),
+        )?;
+
+        pay_pool_rewards(
+            pool,
+            user,
+            _ctx.accounts.lfg_token_program.to_account_info(),
+            _ctx.remaining_accounts,
+        )?;
         user.calculate_lfg_reward_debt(&pool)?;
 
         emit!(UserHarvested {
@@ -298,63 +838,836 @@ pub mod lfg_staking {
         Ok(())
     }
 
-    pub fn recover_lfg_tokens(ctx: Context<RecoverLfgTokens>) -> Result<()> {
-        let balance = ctx.accounts.lfg_vault.amount;
-        
-        let bump = ctx.bumps.state;    
-        let state_seeds: &[&[&[u8]]] = &[&[&b"state".as_ref(), &[bump]]];
-    
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.lfg_vault.to_account_info(),
-            to: ctx.accounts.ata_to.to_account_info(),
-            authority: ctx.accounts.state.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+    /// Harvests on behalf of a user who has allowed keepers to do so. Rewards
+    /// go to the user's reward recipient exactly as with `harvest`.
+    pub fn harvest_for<'info>(_ctx: Context<'_, '_, '_, 'info, HarvestFor<'info>>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        let user = &mut _ctx.accounts.user;
+
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        user.calculate_lfg_reward_amount(pool)?;
 
-        token::transfer(cpi_ctx.with_signer(state_seeds), balance as u64)?;
+        let total_lfg_reward = pay_lfg_reward(
+            state,
+            user,
+            &_ctx.accounts.lfg_reward_vault,
+            _ctx.accounts.lfg_user_vault.to_account_info(),
+            _ctx.accounts.lfg_token_program.to_account_info(),
+        )?;
 
+        pay_pool_rewards(
+            pool,
+            user,
+            _ctx.accounts.lfg_token_program.to_account_info(),
+            _ctx.remaining_accounts,
+        )?;
+        user.calculate_lfg_reward_debt(pool)?;
+
+        emit!(UserHarvested {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.user.authority,
+            lfg_amount: total_lfg_reward
+        });
         Ok(())
     }
 
-    pub fn recover_fee_tokens(ctx: Context<RecoverFeeTokens>) -> Result<()> {
-        let balance = ctx.accounts.fee_vault.amount;
-        
-        let bump = ctx.bumps.state;    
-        let fee_seeds: &[&[&[u8]]] = &[&[&b"state".as_ref(), &[bump]]];
-    
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.fee_vault.to_account_info(),
-            to: ctx.accounts.ata_to.to_account_info(),
-            authority: ctx.accounts.state.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+    /// Removes the boost of a position whose lock has run out. Anyone may call
+    /// it, so an expired lock cannot keep earning boosted rewards or voting
+    /// power until its owner next touches the position.
+    pub fn unboost(_ctx: Context<Unboost>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        let user = &mut _ctx.accounts.user;
+        let now = _ctx.accounts.clock.unix_timestamp;
+
+        require!(user.unlock_time <= now, ErrorCode::StillLocked);
+
+        pool.update(state, &_ctx.accounts.clock)?;
+        user.calculate_lfg_reward_amount(pool)?;
+        user.update_boosted_amount(pool, now)?;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                false,
+            )?;
+        }
+        user.calculate_lfg_reward_debt(pool)?;
 
-        token::transfer(cpi_ctx.with_signer(fee_seeds), balance as u64)?;
+        emit!(Unboosted {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.user.authority
+        });
+        Ok(())
+    }
 
+    /// Sends all future rewards of this position to `recipient`'s token
+    /// accounts and decides whether anyone may trigger `harvest_for` on it.
+    pub fn set_reward_recipient(
+        _ctx: Context<SetRewardRecipient>,
+        recipient: Pubkey,
+        allow_keeper: bool,
+    ) -> Result<()> {
+        let user = &mut _ctx.accounts.user;
+        user.recipient = recipient;
+        user.allow_keeper = allow_keeper;
+        emit!(RewardRecipientChanged {
+            pool: user.pool,
+            user: user.key(),
+            recipient,
+            allow_keeper
+        });
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct CreateStateFirst<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    /// Restakes the user's pending LFG into the LFG pool without resetting the
+    /// withdrawal lock. Rewards in other tokens stay owed until `harvest`.
+    pub fn compound(_ctx: Context<Compound>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        let user = &mut _ctx.accounts.user;
+        let now = _ctx.accounts.clock.unix_timestamp;
 
-    #[account(
-        init,
-        seeds = [b"state".as_ref()],
-        bump,
-        payer = authority,
-        space = 8 + size_of::<StateAccount>()
-    )]
-    pub state: Account<'info, StateAccount>,
+        pool.update(state, &_ctx.accounts.clock)?;
 
-    pub lfg_reward_mint: Box<Account<'info, Mint>>,
+        user.calculate_lfg_reward_amount(&pool)?;
 
-    #[account(
-        init,
+        let pending_lfg_reward = user.lfg_reward_amount.checked_add(user.lfg_extra_reward).ok_or(ErrorCode::MathOverflow)?;
+        let amount: u64 = pending_lfg_reward
+            .min(u128::from(_ctx.accounts.lfg_reward_vault.amount))
+            .try_into()
+            .map_err(|_| ErrorCode::MathOverflow)?;
+
+        user.lfg_reward_amount = pending_lfg_reward - u128::from(amount);
+        user.lfg_extra_reward = 0;
+        state.lfg_paid = state.lfg_paid.checked_add(u128::from(amount)).ok_or(ErrorCode::MathOverflow)?;
+        user.amount = user.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        pool.amount = pool.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        user.update_boosted_amount(pool, now)?;
+        if pool.key() == state.governance_pool {
+            record_voting_power(
+                &mut _ctx.accounts.voter_checkpoint,
+                &mut _ctx.accounts.total_checkpoint,
+                &mut _ctx.accounts.voter_weight_record,
+                user,
+                pool,
+                _ctx.accounts.clock.slot,
+                true,
+            )?;
+        }
+        user.calculate_lfg_reward_debt(&pool)?;
+
+        let cpi_accounts = Transfer {
+            from: _ctx.accounts.lfg_reward_vault.to_account_info(),
+            to: _ctx.accounts.pool_vault.to_account_info(),
+            authority: state.to_account_info(),
+        };
+        let seeds = &[b"state".as_ref(), &[state.bump]];
+        let signer = &[&seeds[..]];
+        let cpi_program = _ctx.accounts.lfg_token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        emit!(Compounded {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.authority.key(),
+            amount
+        });
+        Ok(())
+    }
+
+    /// Pays out the user's share of early-withdraw fees redistributed to the
+    /// pool. These are in the pool's own token and are held in its vault.
+    pub fn claim_fee_reward(_ctx: Context<ClaimFeeReward>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let pool = &mut _ctx.accounts.pool;
+        let user = &mut _ctx.accounts.user;
+
+        pool.update(state, &_ctx.accounts.clock)?;
+
+        user.calculate_lfg_reward_amount(pool)?;
+
+        let amount: u64 = user.fee_reward_amount.try_into().map_err(|_| ErrorCode::MathOverflow)?;
+        user.fee_reward_amount = 0;
+        user.calculate_lfg_reward_debt(pool)?;
+
+        let cpi_accounts = Transfer {
+            from: _ctx.accounts.pool_vault.to_account_info(),
+            to: _ctx.accounts.user_vault.to_account_info(),
+            authority: pool.to_account_info(),
+        };
+        let seeds = &[pool.mint.as_ref(), &[pool.bump]];
+        let signer = &[&seeds[..]];
+        let cpi_program = _ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        emit!(FeeRewardClaimed {
+            pool: _ctx.accounts.pool.key(),
+            user: _ctx.accounts.user.key(),
+            authority: _ctx.accounts.authority.key(),
+            amount
+        });
+        Ok(())
+    }
+
+    /// Makes `pool` the source of governance voting power for `realm` and
+    /// starts its total voting power history.
+    pub fn set_governance(
+        _ctx: Context<SetGovernance>,
+        realm: Pubkey,
+        governing_token_mint: Pubkey,
+    ) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        state.realm = realm;
+        state.governing_token_mint = governing_token_mint;
+        state.governance_pool = _ctx.accounts.pool.key();
+
+        let total_checkpoint = &mut _ctx.accounts.total_checkpoint;
+        total_checkpoint.bump = _ctx.bumps.total_checkpoint;
+        total_checkpoint.pool = _ctx.accounts.pool.key();
+        let power = u64::try_from(_ctx.accounts.pool.boosted_amount).map_err(|_| ErrorCode::MathOverflow)?;
+        total_checkpoint.record(_ctx.accounts.clock.slot, power);
+
+        emit!(GovernanceChanged {
+            realm,
+            governing_token_mint,
+            pool: _ctx.accounts.pool.key()
+        });
+        Ok(())
+    }
+
+    /// Creates the user's voting power history and voter weight record for
+    /// the governance pool, or picks them up again after governance moved to
+    /// another pool or realm. Once they exist, deposits into the governance
+    /// pool must pass them; withdrawals never require them.
+    pub fn create_voter(_ctx: Context<CreateVoter>) -> Result<()> {
+        let state = &_ctx.accounts.state;
+        let slot = _ctx.accounts.clock.slot;
+        let power = u64::try_from(_ctx.accounts.user.boosted_amount).map_err(|_| ErrorCode::MathOverflow)?;
+
+        let voter_checkpoint = &mut _ctx.accounts.voter_checkpoint;
+        voter_checkpoint.bump = _ctx.bumps.voter_checkpoint;
+        voter_checkpoint.pool = _ctx.accounts.pool.key();
+        voter_checkpoint.owner = _ctx.accounts.authority.key();
+        voter_checkpoint.record(slot, power);
+
+        let voter_weight_record = &mut _ctx.accounts.voter_weight_record;
+        voter_weight_record.realm = state.realm;
+        voter_weight_record.governing_token_mint = state.governing_token_mint;
+        voter_weight_record.governing_token_owner = _ctx.accounts.authority.key();
+        voter_weight_record.voter_weight = power;
+        voter_weight_record.voter_weight_expiry = Some(slot);
+
+        _ctx.accounts.user.voter_records = true;
+        Ok(())
+    }
+
+    /// Writes the user's current voting power to their voter weight record,
+    /// valid for this slot only. Realms call it in the same transaction as
+    /// the governance action, as voter weight add-ins expect.
+    pub fn update_voter_weight_record(_ctx: Context<UpdateVoterWeightRecord>) -> Result<()> {
+        let voter_weight_record = &mut _ctx.accounts.voter_weight_record;
+        voter_weight_record.voter_weight =
+            u64::try_from(_ctx.accounts.user.boosted_amount).map_err(|_| ErrorCode::MathOverflow)?;
+        voter_weight_record.voter_weight_expiry = Some(_ctx.accounts.clock.slot);
+        Ok(())
+    }
+
+    pub fn voting_power_at(_ctx: Context<ViewVotingPower>, slot: u64) -> Result<u64> {
+        _ctx.accounts.checkpoint.power_at(slot)
+    }
+
+    pub fn pending_rewards(_ctx: Context<PendingRewardsView>) -> Result<PendingRewards> {
+        calculate_pending_rewards(
+            &_ctx.accounts.state,
+            &_ctx.accounts.pool,
+            &_ctx.accounts.user,
+            &_ctx.accounts.clock,
+        )
+    }
+
+    /// Credits `amounts[i]` of bonus LFG to the `i`-th user account in
+    /// `remaining_accounts`, paid out with their next harvest. Every pool comes
+    /// first in `remaining_accounts`, then the users. Batches must arrive in
+    /// `seq` order so a replayed batch is rejected, and the reward vault must
+    /// hold the bonus on top of everything owed or still scheduled.
+    pub fn credit_bonus(_ctx: Context<CreditBonus>, seq: u64, amounts: Vec<u64>) -> Result<()> {
+        let state = &mut _ctx.accounts.state;
+        let now = _ctx.accounts.clock.unix_timestamp;
+
+        check_bonus_seq(state, seq)?;
+        let pool_count = usize::try_from(state.pool_count).map_err(|_| ErrorCode::MathOverflow)?;
+        let (pools, users) = _ctx
+            .remaining_accounts
+            .split_at(pool_count.min(_ctx.remaining_accounts.len()));
+        update_all_pools(state, &_ctx.accounts.clock, pools, None)?;
+        require!(
+            amounts.len() == users.len(),
+            ErrorCode::InvalidBonusBatch
+        );
+
+        let mut total: u128 = 0;
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(amounts.len());
+        for (user_acc_info, amount) in users.iter().zip(amounts.iter()) {
+            require_keys_eq!(*user_acc_info.owner, crate::ID, ErrorCode::InvalidBonusBatch);
+            require!(user_acc_info.is_writable, ErrorCode::InvalidBonusBatch);
+            require!(!seen.contains(&user_acc_info.key()), ErrorCode::InvalidBonusBatch);
+            seen.push(user_acc_info.key());
+
+            let mut user = FarmPoolUserAccount::try_deserialize(&mut &user_acc_info.try_borrow_data()?[..])?;
+            user.lfg_extra_reward = user
+                .lfg_extra_reward
+                .checked_add(u128::from(*amount))
+                .ok_or(ErrorCode::MathOverflow)?;
+            user.try_serialize(&mut &mut user_acc_info.try_borrow_mut_data()?[..])?;
+            total = total.checked_add(u128::from(*amount)).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        check_reward_solvency(state, now, total, _ctx.accounts.lfg_reward_vault.amount)?;
+
+        // Bonuses are owed from now on, so they count toward the recovery reserve.
+        state.lfg_accrued = state.lfg_accrued.checked_add(total).ok_or(ErrorCode::MathOverflow)?;
+        state.bonus_seq = seq;
+        emit!(BonusCredited {
+            seq,
+            users: amounts.len() as u64,
+            amount: u64::try_from(total).map_err(|_| ErrorCode::MathOverflow)?
+        });
+        Ok(())
+    }
+
+    /// Sends `amount` of LFG from the reward vault to the authority. LFG that
+    /// has already been emitted to stakers and not yet paid out is reserved,
+    /// and the emission schedule is shortened by whatever is taken. Every pool
+    /// must be passed in remaining accounts so the reserve is current.
+    pub fn recover_lfg_tokens(ctx: Context<RecoverLfgTokens>, amount: u64) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+        let now = ctx.accounts.clock.unix_timestamp;
+        update_all_pools(state, &ctx.accounts.clock, ctx.remaining_accounts, None)?;
+
+        let reserved = state.lfg_reserved();
+        let recoverable = u128::from(ctx.accounts.lfg_vault.amount).saturating_sub(reserved);
+        require!(u128::from(amount) <= recoverable, ErrorCode::ReservedRewards);
+
+        if let Some(seconds) = u128::from(amount).checked_div(state.emission_per_second()) {
+            let seconds = i64::try_from(seconds).map_err(|_| ErrorCode::MathOverflow)?;
+            state.reward_end_time = state.reward_end_time.saturating_sub(seconds).max(now);
+        }
+
+        let bump = state.bump;
+        let state_seeds: &[&[&[u8]]] = &[&[&b"state".as_ref(), &[bump]]];
+    
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.lfg_vault.to_account_info(),
+            to: ctx.accounts.ata_to.to_account_info(),
+            authority: ctx.accounts.state.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token::transfer(cpi_ctx.with_signer(state_seeds), amount)?;
+
+        emit!(TokensRecovered {
+            vault: ctx.accounts.lfg_vault.key(),
+            to: ctx.accounts.ata_to.key(),
+            amount
+        });
+        Ok(())
+    }
+
+    pub fn recover_fee_tokens(ctx: Context<RecoverFeeTokens>, amount: u64) -> Result<()> {
+        let bump = ctx.accounts.state.bump;
+        let fee_seeds: &[&[&[u8]]] = &[&[&b"state".as_ref(), &[bump]]];
+    
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.fee_vault.to_account_info(),
+            to: ctx.accounts.ata_to.to_account_info(),
+            authority: ctx.accounts.state.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token::transfer(cpi_ctx.with_signer(fee_seeds), amount)?;
+
+        emit!(TokensRecovered {
+            vault: ctx.accounts.fee_vault.key(),
+            to: ctx.accounts.ata_to.key(),
+            amount
+        });
+        Ok(())
+    }
+
+    /// Grows the state created before fields were appended to `StateAccount`
+    /// and creates the registry if it does not exist yet. Every pool, already
+    /// migrated, must be passed in remaining accounts; they are counted,
+    /// weighted and registered, and must account for all of `total_point` and
+    /// every registry entry. The schedule restarts with the reward vault's
+    /// balance as its runway.
+    /// LFG owed to stakers before the migration is not part of the recovery
+    /// reserve, so it must not be recovered.
+    pub fn migrate_state(_ctx: Context<MigrateState>) -> Result<()> {
+        let state_info = _ctx.accounts.state.to_account_info();
+        {
+            let data = state_info.try_borrow_data()?;
+            require!(data.len() >= 8 + 64, ErrorCode::InvalidMigration);
+            let authority = Pubkey::try_from(&data[8..40]).map_err(|_| ErrorCode::InvalidMigration)?;
+            let lfg_reward_vault = Pubkey::try_from(&data[40..72]).map_err(|_| ErrorCode::InvalidMigration)?;
+            require_keys_eq!(authority, _ctx.accounts.authority.key(), ErrorCode::InvalidAuthority);
+            require_keys_eq!(lfg_reward_vault, _ctx.accounts.lfg_reward_vault.key(), ErrorCode::InvalidMigration);
+        }
+        if !grow_account::<StateAccount>(
+            &state_info,
+            &_ctx.accounts.authority.to_account_info(),
+            &_ctx.accounts.system_program.to_account_info(),
+            8 + size_of::<StateAccount>(),
+        )? {
+            return Ok(());
+        }
+
+        let mut state = StateAccount::try_deserialize(&mut &state_info.try_borrow_data()?[..])?;
+        let registry = &mut _ctx.accounts.registry;
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(_ctx.remaining_accounts.len());
+        let mut total_point: u64 = 0;
+        for pool_acc_info in _ctx.remaining_accounts {
+            require_keys_eq!(*pool_acc_info.owner, crate::ID, ErrorCode::InvalidPoolAccount);
+            require!(!seen.contains(&pool_acc_info.key()), ErrorCode::InvalidPoolAccount);
+            seen.push(pool_acc_info.key());
+
+            let pool = FarmPoolAccount::try_deserialize(&mut &pool_acc_info.try_borrow_data()?[..])?;
+            total_point = total_point.checked_add(pool.point).ok_or(ErrorCode::MathOverflow)?;
+            state.total_weight = state.total_weight.checked_add(pool.weight()?).ok_or(ErrorCode::MathOverflow)?;
+            state.pool_count = state.pool_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+            if registry.entry_mut(pool_acc_info.key()).is_err() {
+                registry.register(pool_acc_info.key(), pool.mint, pool.point)?;
+            }
+        }
+        require!(total_point == state.total_point, ErrorCode::MissingPools);
+        require!(
+            registry
+                .entries
+                .iter()
+                .all(|entry| !entry.active || seen.contains(&entry.pool)),
+            ErrorCode::MissingPools
+        );
+
+        let now = _ctx.accounts.clock.unix_timestamp;
+        state.reward_end_time = now;
+        if let Some(seconds) = u128::from(_ctx.accounts.lfg_reward_vault.amount).checked_div(state.emission_per_second()) {
+            let seconds = i64::try_from(seconds).map_err(|_| ErrorCode::MathOverflow)?;
+            state.reward_end_time = now.checked_add(seconds).ok_or(ErrorCode::MathOverflow)?;
+        }
+        state.try_serialize(&mut &mut state_info.try_borrow_mut_data()?[..])?;
+        Ok(())
+    }
+
+    /// Grows a pool created before fields were appended to `FarmPoolAccount`.
+    /// Anyone may call it. The pool keeps its multiplier, or 1 if none was
+    /// set, and the 10% early-withdraw fee every pool used to charge. Every
+    /// stake in it counts as unboosted, so the boosted total is the staked
+    /// amount.
+    pub fn migrate_pool(_ctx: Context<MigrateAccount>) -> Result<()> {
+        let pool_info = _ctx.accounts.account.to_account_info();
+        if grow_account::<FarmPoolAccount>(
+            &pool_info,
+            &_ctx.accounts.payer.to_account_info(),
+            &_ctx.accounts.system_program.to_account_info(),
+            8 + size_of::<FarmPoolAccount>(),
+        )? {
+            let mut pool = FarmPoolAccount::try_deserialize(&mut &pool_info.try_borrow_data()?[..])?;
+            pool.amount_multipler = pool.amount_multipler.max(1);
+            pool.early_withdraw_fee_bps = LEGACY_EARLY_WITHDRAW_FEE_BPS;
+            pool.boosted_amount = u128::from(pool.amount);
+            pool.last_reward_slot = _ctx.accounts.clock.slot;
+            pool.try_serialize(&mut &mut pool_info.try_borrow_mut_data()?[..])?;
+        }
+        Ok(())
+    }
+
+    /// Grows a position created before fields were appended to
+    /// `FarmPoolUserAccount`. Anyone may call it. The position starts out
+    /// unlocked and unboosted, matching how its pool was migrated.
+    pub fn migrate_user(_ctx: Context<MigrateAccount>) -> Result<()> {
+        let user_info = _ctx.accounts.account.to_account_info();
+        if grow_account::<FarmPoolUserAccount>(
+            &user_info,
+            &_ctx.accounts.payer.to_account_info(),
+            &_ctx.accounts.system_program.to_account_info(),
+            8 + size_of::<FarmPoolUserAccount>(),
+        )? {
+            let mut user = FarmPoolUserAccount::try_deserialize(&mut &user_info.try_borrow_data()?[..])?;
+            user.boost_bps = BPS_DENOMINATOR;
+            user.boosted_amount = u128::from(user.amount);
+            user.initialized = true;
+            user.try_serialize(&mut &mut user_info.try_borrow_mut_data()?[..])?;
+        }
+        Ok(())
+    }
+}
+
+/// What `user` could harvest from `pool` at `clock`. Runs the same update and
+/// reward math as the instructions on copies of the accounts, so clients can
+/// call it off-chain with fetched account data.
+pub fn calculate_pending_rewards(
+    state: &StateAccount,
+    pool: &FarmPoolAccount,
+    user: &FarmPoolUserAccount,
+    clock: &Clock,
+) -> Result<PendingRewards> {
+    let mut state = state.clone();
+    let mut pool = pool.clone();
+    let mut user = user.clone();
+    pool.update(&mut state, clock)?;
+    user.calculate_lfg_reward_amount(&pool)?;
+
+    Ok(PendingRewards {
+        lfg_reward: user.lfg_reward_amount,
+        lfg_extra_reward: user.lfg_extra_reward,
+        rewards: user.reward_amounts,
+        fee_reward: user.fee_reward_amount,
+        unlock_time: user.unlock_time,
+        fee_free_time: user.last_stake_time.saturating_add(pool.lock_duration),
+    })
+}
+
+/// Bonus batches must arrive one after another, so a replayed or skipped
+/// batch is rejected.
+fn check_bonus_seq(state: &StateAccount, seq: u64) -> Result<()> {
+    require!(
+        state.bonus_seq.checked_add(1) == Some(seq),
+        ErrorCode::InvalidSEQ
+    );
+    Ok(())
+}
+
+/// Checks that `vault_amount` covers the LFG owed to stakers, what the
+/// schedule will still emit after `now`, and `extra` on top.
+fn check_reward_solvency(state: &StateAccount, now: i64, extra: u128, vault_amount: u64) -> Result<()> {
+    let seconds_left = u128::try_from(state.reward_end_time.saturating_sub(now).max(0))
+        .map_err(|_| ErrorCode::MathOverflow)?;
+    let scheduled = state
+        .emission_per_second()
+        .checked_mul(seconds_left)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let committed = state
+        .lfg_reserved()
+        .checked_add(scheduled)
+        .and_then(|committed| committed.checked_add(extra))
+        .ok_or(ErrorCode::MathOverflow)?;
+    require!(
+        committed <= u128::from(vault_amount),
+        ErrorCode::InsufficientRewardVault
+    );
+    Ok(())
+}
+
+/// Brings every registered pool up to date before the emission rate, the
+/// schedule or the point total changes. `remaining_accounts` must hold each
+/// pool exactly once, except `skip`, which the caller updates itself.
+fn update_all_pools(
+    state: &mut StateAccount,
+    clock: &Clock,
+    remaining_accounts: &[AccountInfo],
+    skip: Option<Pubkey>,
+) -> Result<()> {
+    let expected = state
+        .pool_count
+        .checked_sub(u64::from(skip.is_some()))
+        .ok_or(ErrorCode::MathOverflow)?;
+    require!(
+        remaining_accounts.len() as u64 == expected,
+        ErrorCode::MissingPools
+    );
+
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(remaining_accounts.len());
+    for pool_acc_info in remaining_accounts {
+        require_keys_eq!(*pool_acc_info.owner, crate::ID, ErrorCode::InvalidPoolAccount);
+        require!(pool_acc_info.is_writable, ErrorCode::InvalidPoolAccount);
+        require!(
+            Some(pool_acc_info.key()) != skip && !seen.contains(&pool_acc_info.key()),
+            ErrorCode::InvalidPoolAccount
+        );
+        seen.push(pool_acc_info.key());
+
+        let mut pool = FarmPoolAccount::try_deserialize(&mut &pool_acc_info.try_borrow_data()?[..])?;
+        pool.update(state, clock)?;
+        pool.try_serialize(&mut &mut pool_acc_info.try_borrow_mut_data()?[..])?;
+    }
+    Ok(())
+}
+
+/// Records voting power after a stake change in the governance pool: the
+/// user's and the pool's checkpoints and the user's voter weight record.
+/// When `required`, the pool's checkpoint and, if the user has created them,
+/// the user's records must be passed. Otherwise whatever is passed is
+/// recorded, so leaving the pool never depends on the governance accounts.
+fn record_voting_power<'info>(
+    voter_checkpoint: &mut Option<Box<Account<'info, VoterCheckpoint>>>,
+    total_checkpoint: &mut Option<Box<Account<'info, VoterCheckpoint>>>,
+    voter_weight_record: &mut Option<Box<Account<'info, VoterWeightRecord>>>,
+    user: &FarmPoolUserAccount,
+    pool: &FarmPoolAccount,
+    slot: u64,
+    required: bool,
+) -> Result<()> {
+    if required {
+        require!(
+            total_checkpoint.is_some()
+                && (!user.voter_records || (voter_checkpoint.is_some() && voter_weight_record.is_some())),
+            ErrorCode::MissingVoterAccounts
+        );
+    }
+
+    let power = u64::try_from(user.boosted_amount).map_err(|_| ErrorCode::MathOverflow)?;
+    let total_power = u64::try_from(pool.boosted_amount).map_err(|_| ErrorCode::MathOverflow)?;
+    if let Some(voter_checkpoint) = voter_checkpoint {
+        voter_checkpoint.record(slot, power);
+    }
+    if let Some(total_checkpoint) = total_checkpoint {
+        total_checkpoint.record(slot, total_power);
+    }
+    if let Some(voter_weight_record) = voter_weight_record {
+        voter_weight_record.voter_weight = power;
+        voter_weight_record.voter_weight_expiry = Some(slot);
+    }
+    Ok(())
+}
+
+/// Pays the user's pending LFG, bonus included, from the reward vault. Pays
+/// what the vault holds and keeps the rest owed instead of reverting.
+fn pay_lfg_reward<'info>(
+    state: &mut Account<'info, StateAccount>,
+    user: &mut FarmPoolUserAccount,
+    lfg_reward_vault: &Account<'info, TokenAccount>,
+    lfg_user_vault: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+) -> Result<u64> {
+    let pending_lfg_reward = user.lfg_reward_amount.checked_add(user.lfg_extra_reward).ok_or(ErrorCode::MathOverflow)?;
+    let total_lfg_reward: u64 = pending_lfg_reward
+        .min(u128::from(lfg_reward_vault.amount))
+        .try_into()
+        .map_err(|_| ErrorCode::MathOverflow)?;
+
+    let cpi_lfg_accounts = Transfer {
+        from: lfg_reward_vault.to_account_info(),
+        to: lfg_user_vault,
+        authority: state.to_account_info(),
+    };
+
+    let seeds = &[b"state".as_ref(), &[state.bump]];
+    let signer = &[&seeds[..]];
+    let cpi_lfg_ctx = CpiContext::new_with_signer(token_program, cpi_lfg_accounts, signer);
+
+    token::transfer(cpi_lfg_ctx, total_lfg_reward)?;
+    state.lfg_paid = state.lfg_paid.checked_add(u128::from(total_lfg_reward)).ok_or(ErrorCode::MathOverflow)?;
+
+    user.lfg_reward_amount = pending_lfg_reward - u128::from(total_lfg_reward);
+    user.lfg_extra_reward = 0;
+    Ok(total_lfg_reward)
+}
+
+/// Pays out every reward the pool carries besides LFG. `remaining_accounts`
+/// holds a `(reward_vault, user_vault)` pair for each active reward slot, in
+/// slot order. A short reward vault pays what it holds and the rest stays
+/// owed, as with LFG.
+fn pay_pool_rewards<'info>(
+    pool: &mut Account<'info, FarmPoolAccount>,
+    user: &mut FarmPoolUserAccount,
+    token_program: AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    let (mint, bump, rewards) = (pool.mint, pool.bump, pool.rewards);
+    let seeds = &[mint.as_ref(), &[bump]];
+    let signer = &[&seeds[..]];
+    let recipient = user.reward_recipient();
+
+    let iter = &mut remaining_accounts.iter();
+    for (index, reward) in rewards.iter().enumerate() {
+        if reward.mint == Pubkey::default() {
+            continue;
+        }
+        let reward_vault = next_account_info(iter)?;
+        let user_vault = next_account_info(iter)?;
+        require_keys_eq!(reward_vault.key(), reward.vault, ErrorCode::InvalidRewardVault);
+        let user_token = TokenAccount::try_deserialize(&mut &user_vault.try_borrow_data()?[..])?;
+        require_keys_eq!(user_token.owner, recipient, ErrorCode::InvalidRewardVault);
+        require_keys_eq!(user_token.mint, reward.mint, ErrorCode::InvalidRewardVault);
+
+        let owed = user.reward_amounts[index];
+        let vault_token = TokenAccount::try_deserialize(&mut &reward_vault.try_borrow_data()?[..])?;
+        let amount: u64 = owed
+            .min(u128::from(vault_token.amount))
+            .try_into()
+            .map_err(|_| ErrorCode::MathOverflow)?;
+        if amount > 0 {
+            let cpi_accounts = Transfer {
+                from: reward_vault.clone(),
+                to: user_vault.clone(),
+                authority: pool.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+            token::transfer(cpi_ctx, amount)?;
+            pool.reward_paid[index] = pool.reward_paid[index]
+                .checked_add(u128::from(amount))
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+        user.reward_amounts[index] = owed - u128::from(amount);
+
+        emit!(UserHarvestedReward {
+            pool: pool.key(),
+            mint: reward.mint,
+            authority: user.authority,
+            amount
+        });
+    }
+    Ok(())
+}
+
+/// Splits an early-withdraw `fee` by the configured shares and returns the
+/// treasury, burn and redistributed parts. The redistributed part is credited to the pool's
+/// remaining stakers and stays in the pool vault; with nobody left to receive
+/// it, it goes to the treasury instead.
+fn split_fee(state: &StateAccount, pool: &mut FarmPoolAccount, fee: u64) -> Result<(u64, u64, u64)> {
+    let burn_fee = bps_of(fee, state.fee_burn_bps)?;
+    let mut redistributed_fee = bps_of(fee, state.fee_redistribute_bps)?;
+    if pool.boosted_amount > 0 {
+        let fee_per_share = u128::from(redistributed_fee)
+            .checked_mul(ACC_PRECISION)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(pool.boosted_amount)
+            .ok_or(ErrorCode::DivideByZero)?;
+        pool.acc_fee_per_share = pool
+            .acc_fee_per_share
+            .checked_add(fee_per_share)
+            .ok_or(ErrorCode::MathOverflow)?;
+    } else {
+        redistributed_fee = 0;
+    }
+    let treasury_fee = fee
+        .checked_sub(burn_fee)
+        .and_then(|fee| fee.checked_sub(redistributed_fee))
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok((treasury_fee, burn_fee, redistributed_fee))
+}
+
+fn bps_of(amount: u64, bps: u64) -> Result<u64> {
+    u64::try_from(u128::from(amount) * u128::from(bps) / u128::from(BPS_DENOMINATOR))
+        .map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+/// Moves the treasury share of a withdrawal fee to the fee vault and burns
+/// the burn share from the pool vault.
+fn collect_fee(accounts: &Stake, fee_shares: (u64, u64, u64)) -> Result<()> {
+    let (treasury_fee, burn_fee, redistributed_fee) = fee_shares;
+    if treasury_fee == 0 && burn_fee == 0 && redistributed_fee == 0 {
+        return Ok(());
+    }
+    let seeds = &[accounts.pool.mint.as_ref(), &[accounts.pool.bump]];
+    let signer = &[&seeds[..]];
+
+    if treasury_fee > 0 {
+        let cpi_fee_accounts = Transfer {
+            from: accounts.pool_vault.to_account_info(),
+            to: accounts.fee_vault.to_account_info(),
+            authority: accounts.pool.to_account_info(),
+        };
+        let cpi_fee_program = accounts.token_program.to_account_info();
+        let cpi_fee_ctx = CpiContext::new_with_signer(cpi_fee_program, cpi_fee_accounts, signer);
+        token::transfer(cpi_fee_ctx, treasury_fee)?;
+    }
+    if burn_fee > 0 {
+        let cpi_burn_accounts = Burn {
+            mint: accounts.mint.to_account_info(),
+            from: accounts.pool_vault.to_account_info(),
+            authority: accounts.pool.to_account_info(),
+        };
+        let cpi_burn_program = accounts.token_program.to_account_info();
+        let cpi_burn_ctx = CpiContext::new_with_signer(cpi_burn_program, cpi_burn_accounts, signer);
+        token::burn(cpi_burn_ctx, burn_fee)?;
+    }
+
+    emit!(FeeDistributed {
+        pool: accounts.pool.key(),
+        treasury: treasury_fee,
+        burned: burn_fee,
+        redistributed: redistributed_fee
+    });
+    Ok(())
+}
+
+fn validate_pool_lock(lock_duration: i64, early_withdraw_fee_bps: u64) -> Result<()> {
+    require!(
+        (0..=MAX_LOCK_DURATION).contains(&lock_duration),
+        ErrorCode::InvalidLockDuration
+    );
+    require!(
+        early_withdraw_fee_bps <= MAX_EARLY_WITHDRAW_FEE_BPS,
+        ErrorCode::InvalidWithdrawFee
+    );
+    Ok(())
+}
+
+/// Grows a program account created with an older, shorter layout of `T` to
+/// `new_len`, with `payer` covering the extra rent. The appended bytes are
+/// zeroed, so fields added to the end of `T` read as zero. Returns whether
+/// the account had to grow.
+fn grow_account<'info, T: Discriminator>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<bool> {
+    require_keys_eq!(*account.owner, crate::ID, ErrorCode::InvalidMigration);
+    require!(
+        account.try_borrow_data()?.get(..8) == Some(&T::DISCRIMINATOR[..]),
+        ErrorCode::InvalidMigration
+    );
+    if account.data_len() >= new_len {
+        return Ok(false);
+    }
+
+    let lamports = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account.lamports());
+    if lamports > 0 {
+        let cpi_accounts = system_program::Transfer {
+            from: payer.clone(),
+            to: account.clone(),
+        };
+        let cpi_ctx = CpiContext::new(system_program.clone(), cpi_accounts);
+        system_program::transfer(cpi_ctx, lamports)?;
+    }
+    account.realloc(new_len, true)?;
+    Ok(true)
+}
+
+#[derive(Accounts)]
+pub struct CreateStateFirst<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        seeds = [b"state".as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + size_of::<StateAccount>()
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    pub lfg_reward_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init,
         token::mint = lfg_reward_mint,
         token::authority = state,
         seeds = [b"vault".as_ref(), lfg_reward_mint.key().as_ref()],
@@ -427,6 +1740,19 @@ pub struct Fund<'info> {
         constraint = lfg_token_program.key == &token::ID
     )]
     pub lfg_token_program: Program<'info, Token>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct RewardRunway<'info> {
+    #[account(
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    pub clock: Sysvar<'info, Clock>,
 }
 
 #[derive(Accounts)]
@@ -446,6 +1772,19 @@ pub struct SetAuthority<'info> {
     pub rent: Sysvar<'info, Rent>
 }
 
+#[derive(Accounts)]
+pub struct SetFeeSplit<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
+}
+
 #[derive(Accounts)]
 pub struct ChangeTokensPerSecond<'info> {
     #[account(mut)]
@@ -459,9 +1798,45 @@ pub struct ChangeTokensPerSecond<'info> {
     )]
     pub state: Account<'info, StateAccount>,
 
+    #[account(address = state.lfg_reward_vault)]
+    pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
+
     pub clock: Sysvar<'info, Clock>,
 }
 
+#[derive(Accounts)]
+pub struct CreatePoolRegistry<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        init,
+        seeds = [b"registry".as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + size_of::<PoolRegistry>()
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ViewPoolRegistry<'info> {
+    #[account(
+        seeds = [b"registry".as_ref()],
+        bump
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+}
+
 #[derive(Accounts)]
 pub struct CreateFarmPool<'info> {
     #[account(mut)]
@@ -484,6 +1859,13 @@ pub struct CreateFarmPool<'info> {
     )]
     pub state: Account<'info, StateAccount>,
 
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+
     pub mint: Box<Account<'info, Mint>>,
 
     #[account(
@@ -526,11 +1908,36 @@ pub struct CloseFarmPool<'info> {
     )]
     pub pool: Account<'info, FarmPoolAccount>,
 
-    pub system_program: Program<'info, System>,
-    pub clock: Sysvar<'info, Clock>,
-}
-
-#[derive(Accounts)]
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+
+    #[account(
+        mut,
+        address = pool.vault
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = authority_vault.mint == pool.mint,
+        constraint = authority_vault.owner == authority.key()
+    )]
+    pub authority_vault: Box<Account<'info, TokenAccount>>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        constraint = token_program.key == &token::ID
+    )]
+    pub token_program: Program<'info, Token>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
 pub struct ChangePoolSetting<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
@@ -553,6 +1960,114 @@ pub struct ChangePoolSetting<'info> {
     pub clock: Sysvar<'info, Clock>,
 }
 
+#[derive(Accounts)]
+pub struct AddPoolReward<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        has_one = authority
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    pub reward_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init,
+        token::mint = reward_mint,
+        token::authority = pool,
+        seeds = [b"reward".as_ref(), pool.key().as_ref(), reward_mint.key().as_ref()],
+        bump,
+        payer = authority,
+    )]
+    pub reward_vault: Box<Account<'info, TokenAccount>>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        constraint = token_program.key == &token::ID
+    )]
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimPoolReward<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        has_one = authority
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(mut)]
+    pub reward_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        constraint = authority_vault.owner == authority.key()
+    )]
+    pub authority_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        constraint = token_program.key == &token::ID
+    )]
+    pub token_program: Program<'info, Token>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct ChangePoolPoint<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        has_one = authority
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
 #[derive(Accounts)]
 pub struct CreatePoolUser<'info> {
     #[account(mut)]
@@ -589,6 +2104,29 @@ pub struct CreatePoolUser<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct ClosePoolUser<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), authority.key().as_ref()],
+        bump = user.bump,
+        has_one = pool,
+        has_one = authority,
+        close = authority
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+}
+
 #[derive(Accounts)]
 pub struct Stake<'info> {
     #[account(mut)]
@@ -618,13 +2156,14 @@ pub struct Stake<'info> {
     pub pool: Account<'info, FarmPoolAccount>,
 
     #[account(
+        mut,
         constraint = mint.key() == pool.mint
     )]
     pub mint: Box<Account<'info, Mint>>,
 
     #[account(
-        mut, 
-        constraint = pool_vault.owner == pool.key()
+        mut,
+        address = pool.vault
     )]
     pub pool_vault: Box<Account<'info, TokenAccount>>,
 
@@ -648,6 +2187,113 @@ pub struct Stake<'info> {
     pub token_program: Program<'info, Token>,
 
     pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref(), authority.key().as_ref()],
+        bump = voter_checkpoint.bump
+    )]
+    pub voter_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref()],
+        bump = total_checkpoint.bump
+    )]
+    pub total_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub voter_weight_record: Option<Box<Account<'info, VoterWeightRecord>>>,
+}
+
+#[derive(Accounts)]
+pub struct DepositFor<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: only used as the owner of the position being deposited into.
+    pub beneficiary: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        seeds = [pool.key().as_ref(), beneficiary.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + size_of::<FarmPoolUserAccount>()
+    )]
+    pub user: Box<Account<'info, FarmPoolUserAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault
+    )]
+    pub pool_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        constraint = payer_vault.owner == payer.key()
+    )]
+    pub payer_vault: Box<Account<'info, TokenAccount>>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        constraint = token_program.key == &token::ID
+    )]
+    pub token_program: Program<'info, Token>,
+
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref(), beneficiary.key().as_ref()],
+        bump = voter_checkpoint.bump
+    )]
+    pub voter_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref()],
+        bump = total_checkpoint.bump
+    )]
+    pub total_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            beneficiary.key().as_ref()
+        ],
+        bump
+    )]
+    pub voter_weight_record: Option<Box<Account<'info, VoterWeightRecord>>>,
 }
 
 #[derive(Accounts)]
@@ -685,13 +2331,13 @@ pub struct Harvest<'info> {
     
     #[account(
         mut,
-        constraint = lfg_reward_vault.owner == state.key()
+        address = state.lfg_reward_vault
     )]
     pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
 
     #[account(
         mut,
-        constraint = lfg_user_vault.owner == authority.key()
+        constraint = lfg_user_vault.owner == user.reward_recipient()
     )]
     pub lfg_user_vault: Box<Account<'info, TokenAccount>>,
 
@@ -706,56 +2352,473 @@ pub struct Harvest<'info> {
 }
 
 #[derive(Accounts)]
-pub struct RecoverLfgTokens<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+pub struct HarvestFor<'info> {
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), user.authority.as_ref()],
+        bump = user.bump,
+        has_one = pool,
+        constraint = user.allow_keeper || user.authority == keeper.key() @ ErrorCode::KeeperNotAllowed
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
 
     #[account(
         mut,
         seeds = [b"state".as_ref()],
-        bump
+        bump = state.bump
     )]
     pub state: Account<'info, StateAccount>,
 
-    pub lfg_token_mint: Box<Account<'info, Mint>>,
-    
     #[account(
         mut,
-        token::mint = lfg_token_mint,
-        token::authority = state,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
     )]
-    pub lfg_vault: Box<Account<'info, TokenAccount>>, 
+    pub pool: Account<'info, FarmPoolAccount>,
 
     #[account(
-        init_if_needed,
-        payer = authority,
-        associated_token::mint = lfg_token_mint,
-        associated_token::authority = authority,
+        mut,
+        address = state.lfg_reward_vault
     )]
-    pub ata_to: Box<Account<'info, TokenAccount>>,
-
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
-
-#[derive(Accounts)]
-pub struct RecoverFeeTokens<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
 
     #[account(
         mut,
-        seeds = [b"state".as_ref()],
-        bump
+        constraint = lfg_user_vault.owner == user.reward_recipient()
     )]
-    pub state: Account<'info, StateAccount>,
+    pub lfg_user_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        constraint = lfg_token_program.key == &token::ID
+    )]
+    pub lfg_token_program: Program<'info, Token>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Unboost<'info> {
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), user.authority.as_ref()],
+        bump = user.bump,
+        has_one = pool
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref(), user.authority.as_ref()],
+        bump = voter_checkpoint.bump
+    )]
+    pub voter_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref()],
+        bump = total_checkpoint.bump
+    )]
+    pub total_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            user.authority.as_ref()
+        ],
+        bump
+    )]
+    pub voter_weight_record: Option<Box<Account<'info, VoterWeightRecord>>>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardRecipient<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [user.pool.as_ref(), authority.key().as_ref()],
+        bump = user.bump,
+        has_one = authority
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+}
+
+#[derive(Accounts)]
+pub struct Compound<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), authority.key().as_ref()],
+        bump = user.bump,
+        has_one = pool,
+        has_one = authority
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        constraint = pool.mint == lfg_reward_vault.mint @ ErrorCode::NotLfgPool
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault
+    )]
+    pub pool_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        address = state.lfg_reward_vault
+    )]
+    pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        constraint = lfg_token_program.key == &token::ID
+    )]
+    pub lfg_token_program: Program<'info, Token>,
+
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref(), authority.key().as_ref()],
+        bump = voter_checkpoint.bump
+    )]
+    pub voter_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref()],
+        bump = total_checkpoint.bump
+    )]
+    pub total_checkpoint: Option<Box<Account<'info, VoterCheckpoint>>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub voter_weight_record: Option<Box<Account<'info, VoterWeightRecord>>>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFeeReward<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), authority.key().as_ref()],
+        bump = user.bump,
+        has_one = pool,
+        has_one = authority
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault
+    )]
+    pub pool_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        constraint = user_vault.mint == pool.mint,
+        constraint = user_vault.owner == user.reward_recipient()
+    )]
+    pub user_vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        constraint = token_program.key == &token::ID
+    )]
+    pub token_program: Program<'info, Token>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct SetGovernance<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + size_of::<VoterCheckpoint>()
+    )]
+    pub total_checkpoint: Box<Account<'info, VoterCheckpoint>>,
+
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct CreateVoter<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        constraint = pool.key() == state.governance_pool @ ErrorCode::InvalidVoterAccount
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), authority.key().as_ref()],
+        bump = user.bump,
+        has_one = pool,
+        has_one = authority
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"checkpoint".as_ref(), pool.key().as_ref(), authority.key().as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + size_of::<VoterCheckpoint>()
+    )]
+    pub voter_checkpoint: Box<Account<'info, VoterCheckpoint>>,
+
+    #[account(
+        init_if_needed,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            authority.key().as_ref()
+        ],
+        bump,
+        payer = authority,
+        space = VOTER_WEIGHT_RECORD_SIZE
+    )]
+    pub voter_weight_record: Box<Account<'info, VoterWeightRecord>>,
+
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateVoterWeightRecord<'info> {
+    #[account(
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump,
+        constraint = pool.key() == state.governance_pool @ ErrorCode::InvalidVoterAccount
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        seeds = [pool.key().as_ref(), user.authority.as_ref()],
+        bump = user.bump,
+        has_one = pool
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"voter-weight-record".as_ref(),
+            pool.key().as_ref(),
+            state.realm.as_ref(),
+            state.governing_token_mint.as_ref(),
+            user.authority.as_ref()
+        ],
+        bump
+    )]
+    pub voter_weight_record: Box<Account<'info, VoterWeightRecord>>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct ViewVotingPower<'info> {
+    pub checkpoint: Box<Account<'info, VoterCheckpoint>>,
+}
+
+#[derive(Accounts)]
+pub struct PendingRewardsView<'info> {
+    #[account(
+        seeds = [b"state".as_ref()],
+        bump = state.bump
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        seeds = [pool.mint.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, FarmPoolAccount>,
+
+    #[account(
+        seeds = [pool.key().as_ref(), user.authority.as_ref()],
+        bump = user.bump,
+        has_one = pool
+    )]
+    pub user: Account<'info, FarmPoolUserAccount>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct CreditBonus<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    #[account(
+        address = state.lfg_reward_vault
+    )]
+    pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct RecoverLfgTokens<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
+
+    pub lfg_token_mint: Box<Account<'info, Mint>>,
+    
+    #[account(
+        mut,
+        address = state.lfg_reward_vault,
+        token::mint = lfg_token_mint,
+        token::authority = state,
+    )]
+    pub lfg_vault: Box<Account<'info, TokenAccount>>, 
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = lfg_token_mint,
+        associated_token::authority = authority,
+    )]
+    pub ata_to: Box<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct RecoverFeeTokens<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump = state.bump,
+        has_one = authority
+    )]
+    pub state: Account<'info, StateAccount>,
 
     pub lfg_token_mint: Box<Account<'info, Mint>>,
     
     #[account(
         mut,
+        address = state.fee_vault,
         token::mint = lfg_token_mint,
         token::authority = state,
     )]
@@ -775,16 +2838,158 @@ pub struct RecoverFeeTokens<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: may still hold the older, shorter layout, which does not
+    /// deserialize, so `migrate_state` checks it by hand.
+    #[account(
+        mut,
+        seeds = [b"state".as_ref()],
+        bump
+    )]
+    pub state: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"registry".as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + size_of::<PoolRegistry>()
+    )]
+    pub registry: Box<Account<'info, PoolRegistry>>,
+
+    pub lfg_reward_vault: Box<Account<'info, TokenAccount>>,
+
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAccount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: may still hold the older, shorter layout, which does not
+    /// deserialize; its owner and discriminator are checked by hand.
+    #[account(mut)]
+    pub account: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[account]
+#[derive(Default)]
+pub struct StateAccount {
+    pub authority: Pubkey,
+    pub lfg_reward_vault: Pubkey,
+    pub fee_vault: Pubkey,
+    pub bump: u8,
+    pub total_point: u64,
+    pub start_time: i64,
+    pub token_per_second: u64,
+    pub reward_end_time: i64,
+    pub pool_count: u64,
+    pub fee_treasury_bps: u64,
+    pub fee_burn_bps: u64,
+    pub fee_redistribute_bps: u64,
+    pub lfg_accrued: u128,
+    pub lfg_paid: u128,
+    pub bonus_seq: u64,
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub governance_pool: Pubkey,
+    pub token_per_slot: u64,
+    pub total_weight: u128,
+}
+
+impl StateAccount {
+    /// Most LFG the schedule emits per second: the per-second rate plus the
+    /// per-slot rate at the nominal slot time. Runway and solvency use it.
+    fn emission_per_second(&self) -> u128 {
+        u128::from(self.token_per_second)
+            + u128::from(self.token_per_slot) * 1_000 / u128::from(DEFAULT_MS_PER_SLOT)
+    }
+
+    /// Moves `reward_end_time` after a rate change so the LFG left to emit
+    /// after `now` stays what it was at `old_emission_per_second`. Nothing is
+    /// scheduled while emission is off, so restarting it schedules whatever of
+    /// `vault_amount` is not reserved for rewards already credited.
+    fn rescale_schedule(&mut self, now: i64, old_emission_per_second: u128, vault_amount: u64) -> Result<()> {
+        let emission_per_second = self.emission_per_second();
+        if emission_per_second > 0 && old_emission_per_second == 0 {
+            let unscheduled = u128::from(vault_amount).saturating_sub(self.lfg_reserved());
+            let seconds = i64::try_from(unscheduled / emission_per_second).map_err(|_| ErrorCode::MathOverflow)?;
+            self.reward_end_time = now.checked_add(seconds).ok_or(ErrorCode::MathOverflow)?;
+        } else if emission_per_second > 0 && self.reward_end_time > now {
+            let remaining = u128::try_from(self.reward_end_time - now)
+                .map_err(|_| ErrorCode::MathOverflow)?
+                .checked_mul(old_emission_per_second)
+                .ok_or(ErrorCode::MathOverflow)?;
+            let seconds = i64::try_from(remaining / emission_per_second).map_err(|_| ErrorCode::MathOverflow)?;
+            self.reward_end_time = now.checked_add(seconds).ok_or(ErrorCode::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// LFG credited to staked pools or as bonuses and not yet paid out of the
+    /// reward vault or forfeited.
+    fn lfg_reserved(&self) -> u128 {
+        self.lfg_accrued.saturating_sub(self.lfg_paid)
+    }
+}
+
 #[account]
 #[derive(Default)]
-pub struct StateAccount {
-    pub authority: Pubkey,
-    pub lfg_reward_vault: Pubkey,
-    pub fee_vault: Pubkey,
-    pub bump: u8,
-    pub total_point: u64,
-    pub start_time: i64,
-    pub token_per_second: u64,
+pub struct PoolRegistry {
+    pub entries: [PoolRegistryEntry; MAX_POOLS],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PoolRegistryEntry {
+    pub pool: Pubkey,
+    pub mint: Pubkey,
+    pub point: u64,
+    pub active: bool,
+}
+
+impl PoolRegistry {
+    fn register(&mut self, pool: Pubkey, mint: Pubkey, point: u64) -> Result<()> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| !entry.active)
+            .ok_or(ErrorCode::RegistryFull)?;
+        *entry = PoolRegistryEntry {
+            pool,
+            mint,
+            point,
+            active: true,
+        };
+        Ok(())
+    }
+
+    fn entry_mut(&mut self, pool: Pubkey) -> Result<&mut PoolRegistryEntry> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.active && entry.pool == pool)
+            .ok_or(error!(ErrorCode::InvalidPoolAccount))
+    }
+
+    fn set_point(&mut self, pool: Pubkey, point: u64) -> Result<()> {
+        self.entry_mut(pool)?.point = point;
+        Ok(())
+    }
+
+    fn deactivate(&mut self, pool: Pubkey) -> Result<()> {
+        let entry = self.entry_mut(pool)?;
+        entry.active = false;
+        entry.point = 0;
+        Ok(())
+    }
 }
 
 #[account]
@@ -801,36 +3006,145 @@ pub struct FarmPoolAccount {
     pub acc_lfg_reward_per_share: u128,
     pub amount_multipler: u64,
     pub total_user: u64,
+    pub early_withdraw_fee_bps: u64,
+    pub boosted_amount: u128,
+    pub rewards: [PoolReward; MAX_POOL_REWARDS],
+    pub acc_fee_per_share: u128,
+    pub start_time: i64,
+    pub accrue_per_slot: bool,
+    pub last_reward_slot: u64,
+    pub reward_accrued: [u128; MAX_POOL_REWARDS],
+    pub reward_paid: [u128; MAX_POOL_REWARDS],
+}
+
+/// A partner reward emitted by a single pool on top of LFG. A slot is unused
+/// while `mint` is the default key. The pool tracks how much of each reward
+/// has accrued to stakers and how much has been paid out or forfeited, in
+/// `reward_accrued` and `reward_paid`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PoolReward {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub token_per_second: u64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub acc_reward_per_share: u128,
 }
 
 impl FarmPoolAccount {
-    fn update<'info>(&mut self, state: &StateAccount, clock: &Sysvar<'info, Clock>) -> Result<()> {
-        let seconds = u128::try_from(
-            clock
-                .unix_timestamp
-                .checked_sub(self.last_reward_time)
-                .unwrap(),
-        )
-        .unwrap();
-        let mut lfg_reward_per_share: u128 = 0;
-        if self.amount > 0 && seconds > 0 && self.point > 0 {
-            lfg_reward_per_share = u128::from(state.token_per_second)
+    /// Weight of the pool in the LFG emission, out of `state.total_weight`.
+    ///
+    /// `amount_multipler` scales the pool's points, so a pool whose raw token
+    /// amounts are not comparable with the others', such as an LP token with
+    /// different decimals, can be given a fair share of the emission without
+    /// renumbering the points of every other pool.
+    fn weight(&self) -> Result<u128> {
+        u128::from(self.point)
+            .checked_mul(u128::from(self.amount_multipler))
+            .ok_or(error!(ErrorCode::MathOverflow))
+    }
+
+    /// Sets the pool's points and multiplier and moves `state.total_weight` by
+    /// the change in weight. The accumulators are left alone, so every reward
+    /// debt recorded against them stays valid; the pool must be up to date.
+    fn reweight(&mut self, state: &mut StateAccount, point: u64, amount_multipler: u64) -> Result<()> {
+        state.total_weight = state.total_weight.checked_sub(self.weight()?).ok_or(ErrorCode::MathOverflow)?;
+        self.point = point;
+        self.amount_multipler = amount_multipler;
+        state.total_weight = state.total_weight.checked_add(self.weight()?).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Accrues rewards up to `clock`. Nothing accrues before `start_time` or
+    /// after `reward_end_time`, and a clock that runs backwards accrues nothing
+    /// rather than failing or counting the same time twice.
+    ///
+    /// The LFG credited to the pool's stakers is added to `state.lfg_accrued`.
+    /// Nothing is credited while the pool is empty.
+    fn update(&mut self, state: &mut StateAccount, clock: &Clock) -> Result<()> {
+        let now = clock.unix_timestamp;
+        let lfg_reward = if self.accrue_per_slot {
+            // Slots carry no timestamp, so they are assumed evenly spread over
+            // the time since the last update and only the share that falls
+            // between `start_time` and `reward_end_time` counts.
+            let slots = u128::from(clock.slot.saturating_sub(self.last_reward_slot));
+            let elapsed = now.saturating_sub(self.last_reward_time);
+            let slots = if elapsed <= 0 {
+                if self.start_time <= now && now < state.reward_end_time {
+                    slots
+                } else {
+                    0
+                }
+            } else {
+                let from = self.last_reward_time.max(self.start_time);
+                let window = u128::try_from(now.min(state.reward_end_time).saturating_sub(from).max(0))
+                    .map_err(|_| ErrorCode::MathOverflow)?;
+                slots
+                    .checked_mul(window)
+                    .ok_or(ErrorCode::MathOverflow)?
+                    / u128::try_from(elapsed).map_err(|_| ErrorCode::MathOverflow)?
+            };
+            u128::from(state.token_per_slot)
+                .checked_mul(slots)
+                .ok_or(ErrorCode::MathOverflow)?
+        } else {
+            let from = self.last_reward_time.max(self.start_time);
+            let seconds = u128::try_from(now.min(state.reward_end_time).saturating_sub(from).max(0))
+                .map_err(|_| ErrorCode::MathOverflow)?;
+            u128::from(state.token_per_second)
                 .checked_mul(seconds)
-                .unwrap()
-                .checked_mul(u128::from(self.point))
-                .unwrap()
+                .ok_or(ErrorCode::MathOverflow)?
+        };
+        let mut lfg_reward_per_share: u128 = 0;
+        let weight = self.weight()?;
+        if self.boosted_amount > 0 && lfg_reward > 0 && weight > 0 {
+            let credited = lfg_reward
+                .checked_mul(weight)
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(state.total_weight)
+                .ok_or(ErrorCode::DivideByZero)?;
+            state.lfg_accrued = state.lfg_accrued.checked_add(credited).ok_or(ErrorCode::MathOverflow)?;
+            lfg_reward_per_share = lfg_reward
+                .checked_mul(weight)
+                .ok_or(ErrorCode::MathOverflow)?
                 .checked_mul(ACC_PRECISION)
-                .unwrap()
-                .checked_div(u128::from(state.total_point))
-                .unwrap()
-                .checked_div(u128::from(self.amount))
-                .unwrap();
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(state.total_weight)
+                .ok_or(ErrorCode::DivideByZero)?
+                .checked_div(self.boosted_amount)
+                .ok_or(ErrorCode::DivideByZero)?;
         }
         self.acc_lfg_reward_per_share = self
             .acc_lfg_reward_per_share
             .checked_add(lfg_reward_per_share)
-            .unwrap();
-        self.last_reward_time = clock.unix_timestamp;
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        if self.boosted_amount > 0 {
+            for (index, reward) in self.rewards.iter_mut().enumerate() {
+                let from = self.last_reward_time.max(self.start_time).max(reward.start_time);
+                let to = now.min(reward.end_time);
+                if reward.mint == Pubkey::default() || to <= from {
+                    continue;
+                }
+                let emitted = u128::from(reward.token_per_second)
+                    .checked_mul(u128::try_from(to - from).map_err(|_| ErrorCode::MathOverflow)?)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                self.reward_accrued[index] = self.reward_accrued[index]
+                    .checked_add(emitted)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                let reward_per_share = emitted
+                    .checked_mul(ACC_PRECISION)
+                    .ok_or(ErrorCode::MathOverflow)?
+                    .checked_div(self.boosted_amount)
+                    .ok_or(ErrorCode::DivideByZero)?;
+                reward.acc_reward_per_share = reward
+                    .acc_reward_per_share
+                    .checked_add(reward_per_share)
+                    .ok_or(ErrorCode::MathOverflow)?;
+            }
+        }
+        self.last_reward_time = self.last_reward_time.max(now);
+        self.last_reward_slot = self.last_reward_slot.max(clock.slot);
 
         Ok(())
     }
@@ -847,37 +3161,274 @@ pub struct FarmPoolUserAccount {
     pub lfg_extra_reward: u128,
     pub lfg_reward_debt: u128,
     pub last_stake_time: i64,
+    pub lock_tier: u8,
+    pub boost_bps: u64,
+    pub unlock_time: i64,
+    pub boosted_amount: u128,
+    pub reward_amounts: [u128; MAX_POOL_REWARDS],
+    pub reward_debts: [u128; MAX_POOL_REWARDS],
+    pub fee_reward_amount: u128,
+    pub fee_reward_debt: u128,
+    pub recipient: Pubkey,
+    pub allow_keeper: bool,
+    pub voter_records: bool,
+    pub initialized: bool,
 }
 
 impl FarmPoolUserAccount {
+    /// Owner of the token accounts this position's rewards are paid to.
+    fn reward_recipient(&self) -> Pubkey {
+        if self.recipient == Pubkey::default() {
+            self.authority
+        } else {
+            self.recipient
+        }
+    }
+
+    /// Moves the user into `lock_tier`. An unexpired lock can only be kept or
+    /// extended to a longer tier, and never ends earlier than it already does.
+    fn lock(&mut self, lock_tier: u8, now: i64) -> Result<()> {
+        let (lock_duration, boost_bps) = *LOCK_TIERS
+            .get(usize::from(lock_tier))
+            .ok_or(ErrorCode::InvalidLockTier)?;
+        if self.unlock_time > now {
+            require!(lock_tier >= self.lock_tier, ErrorCode::StillLocked);
+        }
+
+        self.lock_tier = lock_tier;
+        self.boost_bps = boost_bps;
+        self.unlock_time = self.unlock_time.max(now.checked_add(lock_duration).ok_or(ErrorCode::MathOverflow)?);
+        Ok(())
+    }
+
+    /// Moves `last_stake_time` towards `now` by the share `amount` will have
+    /// of the position, so added tokens wait out the fee window in proportion.
+    fn blend_stake_time(&mut self, amount: u64, now: i64) -> Result<()> {
+        let total = u128::from(self.amount)
+            .checked_add(u128::from(amount))
+            .ok_or(ErrorCode::MathOverflow)?;
+        if total == 0 {
+            return Ok(());
+        }
+        let elapsed = u128::try_from(now.saturating_sub(self.last_stake_time).max(0))
+            .map_err(|_| ErrorCode::MathOverflow)?;
+        let remaining = elapsed
+            .checked_mul(u128::from(self.amount))
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(total)
+            .ok_or(ErrorCode::DivideByZero)?;
+        self.last_stake_time = now.saturating_sub(i64::try_from(remaining).map_err(|_| ErrorCode::MathOverflow)?);
+        Ok(())
+    }
+
+    /// Recomputes the boosted stake from `amount` and the current lock tier and
+    /// applies the difference to the pool total. A lock that has run out by
+    /// `now` drops back to the unboosted tier.
+    fn update_boosted_amount(&mut self, pool: &mut FarmPoolAccount, now: i64) -> Result<()> {
+        if self.unlock_time <= now {
+            self.lock_tier = 0;
+        }
+        self.boost_bps = LOCK_TIERS[usize::from(self.lock_tier)].1;
+        let boosted_amount = u128::from(self.amount)
+            .checked_mul(u128::from(self.boost_bps))
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(u128::from(BPS_DENOMINATOR))
+            .ok_or(ErrorCode::DivideByZero)?;
+        pool.boosted_amount = pool
+            .boosted_amount
+            .checked_sub(self.boosted_amount)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_add(boosted_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.boosted_amount = boosted_amount;
+        Ok(())
+    }
+
+    /// LFG the user is owed but has not been paid, bonus included, settled or
+    /// not. Saturates rather than failing, for use on the emergency exit path.
+    fn unsettled_lfg(&self, pool: &FarmPoolAccount) -> u128 {
+        let pending = self
+            .boosted_amount
+            .saturating_mul(pool.acc_lfg_reward_per_share)
+            / ACC_PRECISION;
+        self.lfg_reward_amount
+            .saturating_add(self.lfg_extra_reward)
+            .saturating_add(pending.saturating_sub(self.lfg_reward_debt))
+    }
+
+    /// Partner rewards the user is owed but has not been paid, settled or not.
+    /// Saturates rather than failing, for use on the emergency exit path.
+    fn unsettled_rewards(&self, pool: &FarmPoolAccount) -> [u128; MAX_POOL_REWARDS] {
+        let mut rewards = self.reward_amounts;
+        for (index, reward) in pool.rewards.iter().enumerate() {
+            let pending = self
+                .boosted_amount
+                .saturating_mul(reward.acc_reward_per_share)
+                / ACC_PRECISION;
+            rewards[index] = rewards[index].saturating_add(pending.saturating_sub(self.reward_debts[index]));
+        }
+        rewards
+    }
+
+    /// Adds what accrued since the last settlement to the owed amounts. A debt
+    /// above the accrued total, which rounding can leave, settles as zero.
     fn calculate_lfg_reward_amount<'info>(
         &mut self,
         pool: &FarmPoolAccount,
     ) -> Result<()> {
-        let lfg_pending_amount: u128 = u128::from(self.amount)
+        let lfg_pending_amount: u128 = self
+            .boosted_amount
             .checked_mul(pool.acc_lfg_reward_per_share)
-            .unwrap()
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(ACC_PRECISION)
+            .ok_or(ErrorCode::DivideByZero)?
+            .saturating_sub(self.lfg_reward_debt);
+        self.lfg_reward_amount = self.lfg_reward_amount.checked_add(lfg_pending_amount).ok_or(ErrorCode::MathOverflow)?;
+
+        for (index, reward) in pool.rewards.iter().enumerate() {
+            let pending_amount = self
+                .boosted_amount
+                .checked_mul(reward.acc_reward_per_share)
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(ACC_PRECISION)
+                .ok_or(ErrorCode::DivideByZero)?
+                .saturating_sub(self.reward_debts[index]);
+            self.reward_amounts[index] = self.reward_amounts[index]
+                .checked_add(pending_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        let fee_pending_amount = self
+            .boosted_amount
+            .checked_mul(pool.acc_fee_per_share)
+            .ok_or(ErrorCode::MathOverflow)?
             .checked_div(ACC_PRECISION)
-            .unwrap()
-            .checked_sub(u128::from(self.lfg_reward_debt))
-            .unwrap();
-        self.lfg_reward_amount = self.lfg_reward_amount.checked_add(lfg_pending_amount).unwrap();
+            .ok_or(ErrorCode::DivideByZero)?
+            .saturating_sub(self.fee_reward_debt);
+        self.fee_reward_amount = self.fee_reward_amount.checked_add(fee_pending_amount).ok_or(ErrorCode::MathOverflow)?;
         Ok(())
     }
     fn calculate_lfg_reward_debt<'info>(&mut self, pool: &FarmPoolAccount) -> Result<()> {
-
-        msg!("multiplied {}", u128::from(self.amount).checked_mul(pool.acc_lfg_reward_per_share).unwrap());
-        msg!("scaled {}", u128::from(self.amount).checked_mul(pool.acc_lfg_reward_per_share).unwrap().checked_div(ACC_PRECISION).unwrap());
-
-        self.lfg_reward_debt = u128::from(self.amount)
+        self.lfg_reward_debt = self
+            .boosted_amount
             .checked_mul(pool.acc_lfg_reward_per_share)
-            .unwrap()
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(ACC_PRECISION)
+            .ok_or(ErrorCode::DivideByZero)?;
+
+        for (index, reward) in pool.rewards.iter().enumerate() {
+            self.reward_debts[index] = self
+                .boosted_amount
+                .checked_mul(reward.acc_reward_per_share)
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(ACC_PRECISION)
+                .ok_or(ErrorCode::DivideByZero)?;
+        }
+        self.fee_reward_debt = self
+            .boosted_amount
+            .checked_mul(pool.acc_fee_per_share)
+            .ok_or(ErrorCode::MathOverflow)?
             .checked_div(ACC_PRECISION)
-            .unwrap();
+            .ok_or(ErrorCode::DivideByZero)?;
+
+        #[cfg(feature = "reward-trace")]
+        emit!(RewardCheckpoint {
+            pool: self.pool,
+            user: self.authority,
+            acc_lfg_reward_per_share: pool.acc_lfg_reward_per_share,
+            lfg_reward_debt: self.lfg_reward_debt,
+            lfg_reward_pending: self.lfg_reward_amount
+        });
         Ok(())
     }
 }
 
+/// Voting power history of a user, or of the whole pool when `owner` is the
+/// default key. The newest `VOTER_CHECKPOINTS` changes are kept.
+#[account]
+#[derive(Default)]
+pub struct VoterCheckpoint {
+    pub bump: u8,
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub head: u8,
+    pub len: u8,
+    pub checkpoints: [Checkpoint; VOTER_CHECKPOINTS],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Checkpoint {
+    pub slot: u64,
+    pub power: u64,
+}
+
+impl VoterCheckpoint {
+    /// Appends `power` at `slot`, overwriting the oldest entry once the buffer
+    /// is full. A second change in the same slot replaces the first.
+    fn record(&mut self, slot: u64, power: u64) {
+        if self.len > 0 {
+            let last = (usize::from(self.head) + VOTER_CHECKPOINTS - 1) % VOTER_CHECKPOINTS;
+            if self.checkpoints[last].slot == slot {
+                self.checkpoints[last].power = power;
+                return;
+            }
+        }
+        self.checkpoints[usize::from(self.head)] = Checkpoint { slot, power };
+        self.head = ((usize::from(self.head) + 1) % VOTER_CHECKPOINTS) as u8;
+        self.len = (usize::from(self.len) + 1).min(VOTER_CHECKPOINTS) as u8;
+    }
+
+    /// Voting power in effect at `slot`. Fails when `slot` is older than the
+    /// history still held in the buffer.
+    fn power_at(&self, slot: u64) -> Result<u64> {
+        for back in 1..=usize::from(self.len) {
+            let index = (usize::from(self.head) + VOTER_CHECKPOINTS - back) % VOTER_CHECKPOINTS;
+            if self.checkpoints[index].slot <= slot {
+                return Ok(self.checkpoints[index].power);
+            }
+        }
+        require!(usize::from(self.len) < VOTER_CHECKPOINTS, ErrorCode::CheckpointExpired);
+        Ok(0)
+    }
+}
+
+/// SPL Governance voter weight add-in record. Anchor derives the same
+/// discriminator for it as `spl-governance-addin-api`, so a realm that uses
+/// this program as its voter weight add-in reads it directly. The weight
+/// expires at the slot it was written in; `update_voter_weight_record`
+/// refreshes it for the transaction that uses it.
+#[account]
+#[derive(Default)]
+pub struct VoterWeightRecord {
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub governing_token_owner: Pubkey,
+    pub voter_weight: u64,
+    pub voter_weight_expiry: Option<u64>,
+    pub weight_action: Option<VoterWeightAction>,
+    pub weight_action_target: Option<Pubkey>,
+    pub reserved: [u8; 8],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum VoterWeightAction {
+    CastVote,
+    CommentProposal,
+    CreateGovernance,
+    CreateProposal,
+    SignOffProposal,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PendingRewards {
+    pub lfg_reward: u128,
+    pub lfg_extra_reward: u128,
+    pub rewards: [u128; MAX_POOL_REWARDS],
+    pub fee_reward: u128,
+    pub unlock_time: i64,
+    pub fee_free_time: i64,
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Over staked amount")]
@@ -888,22 +3439,110 @@ pub enum ErrorCode {
     InvalidLockDuration,
     #[msg("Invalid SEQ")]
     InvalidSEQ,
+    #[msg("Invalid early withdraw fee")]
+    InvalidWithdrawFee,
+    #[msg("Amount multiplier must be positive")]
+    InvalidMultiplier,
+    #[msg("Invalid lock tier")]
+    InvalidLockTier,
+    #[msg("Stake is still locked")]
+    StillLocked,
+    #[msg("Invalid reward index")]
+    InvalidRewardIndex,
+    #[msg("Reward slot is already in use")]
+    RewardSlotInUse,
+    #[msg("Reward must end after it starts")]
+    InvalidRewardSchedule,
+    #[msg("Invalid reward vault")]
+    InvalidRewardVault,
+    #[msg("Pool does not stake LFG")]
+    NotLfgPool,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Division by zero")]
+    DivideByZero,
+    #[msg("Reward vault has insufficient funds")]
+    InsufficientRewardVault,
+    #[msg("Every registered pool must be passed in remaining accounts")]
+    MissingPools,
+    #[msg("Invalid pool account")]
+    InvalidPoolAccount,
+    #[msg("Pool registry is full")]
+    RegistryFull,
+    #[msg("Fee shares must add up to 10000 bps")]
+    InvalidFeeSplit,
+    #[msg("Amount exceeds what is not reserved for accrued rewards")]
+    ReservedRewards,
+    #[msg("Invalid bonus batch")]
+    InvalidBonusBatch,
+    #[msg("User does not allow keeper harvests")]
+    KeeperNotAllowed,
+    #[msg("Voter accounts are required for the governance pool")]
+    MissingVoterAccounts,
+    #[msg("Invalid voter account")]
+    InvalidVoterAccount,
+    #[msg("Slot is older than the checkpoint history")]
+    CheckpointExpired,
+    #[msg("Position still holds stake or unclaimed rewards")]
+    PositionNotEmpty,
+    #[msg("Reward has not ended yet")]
+    RewardStillRunning,
+    #[msg("Account cannot be migrated")]
+    InvalidMigration,
+    #[msg("Signer is not the state authority")]
+    InvalidAuthority,
+    #[msg("Amount must be positive")]
+    ZeroAmount,
+    #[msg("Invalid beneficiary")]
+    InvalidBeneficiary,
 }
 #[event]
 pub struct RateChanged {
     token_per_second: u64,
 }
 #[event]
+pub struct RewardFunded {
+    amount: u64,
+    reward_end_time: i64,
+}
+#[event]
 pub struct PoolCreated {
     pool: Pubkey,
     mint: Pubkey,
 }
 #[event]
+pub struct PoolClosed {
+    pool: Pubkey,
+    mint: Pubkey,
+    dust: u64,
+}
+#[event]
 pub struct PoolAmountMultiplerChanged {
     pool: Pubkey,
     amount_multipler: u64,
 }
 #[event]
+pub struct PoolLockChanged {
+    pool: Pubkey,
+    lock_duration: i64,
+    early_withdraw_fee_bps: u64,
+}
+#[event]
+pub struct PoolRewardChanged {
+    pool: Pubkey,
+    mint: Pubkey,
+    token_per_second: u64,
+    start_time: i64,
+    end_time: i64,
+}
+#[event]
+pub struct PoolRewardReclaimed {
+    pool: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    closed: bool,
+}
+#[event]
 pub struct PoolPointChanged {
     pool: Pubkey,
     point: u64,
@@ -915,11 +3554,19 @@ pub struct UserCreated {
     authority: Pubkey,
 }
 #[event]
+pub struct UserClosed {
+    pool: Pubkey,
+    user: Pubkey,
+    authority: Pubkey,
+}
+#[event]
 pub struct Deposit {
     pool: Pubkey,
     user: Pubkey,
     authority: Pubkey,
-    amount: u64
+    amount: u64,
+    lock_tier: u8,
+    unlock_time: i64,
 }
 #[event]
 pub struct Withdraw {
@@ -929,9 +3576,472 @@ pub struct Withdraw {
     amount: u64,
 }
 #[event]
+pub struct EmergencyWithdraw {
+    pool: Pubkey,
+    user: Pubkey,
+    authority: Pubkey,
+    amount: u64,
+    fee: u64,
+}
+#[event]
+pub struct Compounded {
+    pool: Pubkey,
+    user: Pubkey,
+    authority: Pubkey,
+    amount: u64,
+}
+#[event]
 pub struct UserHarvested {
     pool: Pubkey,
     user: Pubkey,
     authority: Pubkey,
     lfg_amount: u64,
 }
+#[event]
+pub struct UserHarvestedReward {
+    pool: Pubkey,
+    mint: Pubkey,
+    authority: Pubkey,
+    amount: u64,
+}
+#[event]
+pub struct RewardCheckpoint {
+    pool: Pubkey,
+    user: Pubkey,
+    acc_lfg_reward_per_share: u128,
+    lfg_reward_debt: u128,
+    lfg_reward_pending: u128,
+}
+#[event]
+pub struct FeeSplitChanged {
+    treasury_bps: u64,
+    burn_bps: u64,
+    redistribute_bps: u64,
+}
+#[event]
+pub struct FeeDistributed {
+    pool: Pubkey,
+    treasury: u64,
+    burned: u64,
+    redistributed: u64,
+}
+#[event]
+pub struct FeeRewardClaimed {
+    pool: Pubkey,
+    user: Pubkey,
+    authority: Pubkey,
+    amount: u64,
+}
+#[event]
+pub struct TokensRecovered {
+    vault: Pubkey,
+    to: Pubkey,
+    amount: u64,
+}
+#[event]
+pub struct BonusCredited {
+    seq: u64,
+    users: u64,
+    amount: u64,
+}
+#[event]
+pub struct Unboosted {
+    pool: Pubkey,
+    user: Pubkey,
+    authority: Pubkey,
+}
+#[event]
+pub struct RewardRecipientChanged {
+    pool: Pubkey,
+    user: Pubkey,
+    recipient: Pubkey,
+    allow_keeper: bool,
+}
+#[event]
+pub struct GovernanceChanged {
+    realm: Pubkey,
+    governing_token_mint: Pubkey,
+    pool: Pubkey,
+}
+#[event]
+pub struct SlotRateChanged {
+    token_per_slot: u64,
+}
+#[event]
+pub struct PoolAccrualChanged {
+    pool: Pubkey,
+    accrue_per_slot: bool,
+}
+#[event]
+pub struct DepositedFor {
+    pool: Pubkey,
+    user: Pubkey,
+    payer: Pubkey,
+    beneficiary: Pubkey,
+    amount: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(slot: u64, unix_timestamp: i64) -> Clock {
+        Clock {
+            slot,
+            unix_timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn state(token_per_second: u64, total_weight: u128) -> StateAccount {
+        StateAccount {
+            token_per_second,
+            reward_end_time: i64::MAX,
+            total_weight,
+            ..Default::default()
+        }
+    }
+
+    fn pool(point: u64, amount_multipler: u64, boosted_amount: u128) -> FarmPoolAccount {
+        FarmPoolAccount {
+            point,
+            amount_multipler,
+            amount: u64::try_from(boosted_amount).unwrap(),
+            boosted_amount,
+            ..Default::default()
+        }
+    }
+
+    fn user(boosted_amount: u128) -> FarmPoolUserAccount {
+        FarmPoolUserAccount {
+            amount: u64::try_from(boosted_amount).unwrap(),
+            boost_bps: BPS_DENOMINATOR,
+            boosted_amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn multiplier_scales_pool_share_of_emission() {
+        let mut single = pool(1, 1, 1_000);
+        let mut triple = pool(1, 3, 1_000);
+        let mut state = state(100, single.weight().unwrap() + triple.weight().unwrap());
+        single.update(&mut state, &clock(0, 10)).unwrap();
+        triple.update(&mut state, &clock(0, 10)).unwrap();
+
+        let mut single_user = user(1_000);
+        let mut triple_user = user(1_000);
+        single_user.calculate_lfg_reward_amount(&single).unwrap();
+        triple_user.calculate_lfg_reward_amount(&triple).unwrap();
+        assert_eq!(single_user.lfg_reward_amount, 250);
+        assert_eq!(triple_user.lfg_reward_amount, 750);
+    }
+
+    #[test]
+    fn multiplier_does_not_change_split_inside_pool() {
+        let mut pool = pool(1, 7, 4_000);
+        let mut state = state(100, pool.weight().unwrap());
+        pool.update(&mut state, &clock(0, 10)).unwrap();
+
+        let mut small = user(1_000);
+        let mut large = user(3_000);
+        small.calculate_lfg_reward_amount(&pool).unwrap();
+        large.calculate_lfg_reward_amount(&pool).unwrap();
+        assert_eq!(small.lfg_reward_amount, 250);
+        assert_eq!(large.lfg_reward_amount, 750);
+    }
+
+    #[test]
+    fn partner_reward_accrues_only_while_staked() {
+        let mut pool = pool(1, 1, 0);
+        pool.rewards[0] = PoolReward {
+            mint: Pubkey::new_unique(),
+            token_per_second: 5,
+            start_time: 0,
+            end_time: 100,
+            ..Default::default()
+        };
+        let mut state = state(0, pool.weight().unwrap());
+        pool.update(&mut state, &clock(0, 10)).unwrap();
+        assert_eq!(pool.reward_accrued[0], 0);
+
+        pool.amount = 1_000;
+        pool.boosted_amount = 1_000;
+        pool.update(&mut state, &clock(0, 150)).unwrap();
+        assert_eq!(pool.reward_accrued[0], 450);
+
+        let user = user(1_000);
+        assert_eq!(user.unsettled_rewards(&pool)[0], 450);
+    }
+
+    #[test]
+    fn expired_lock_drops_boost() {
+        let mut pool = pool(1, 1, 0);
+        let mut user = user(0);
+        user.amount = 1_000;
+        user.lock(2, 0).unwrap();
+        user.update_boosted_amount(&mut pool, 0).unwrap();
+        assert_eq!(user.boosted_amount, 1_500);
+        assert_eq!(pool.boosted_amount, 1_500);
+
+        user.update_boosted_amount(&mut pool, user.unlock_time - 1).unwrap();
+        assert_eq!(user.boosted_amount, 1_500);
+
+        user.update_boosted_amount(&mut pool, user.unlock_time).unwrap();
+        assert_eq!(user.lock_tier, 0);
+        assert_eq!(user.boosted_amount, 1_000);
+        assert_eq!(pool.boosted_amount, 1_000);
+    }
+
+    #[test]
+    fn settlement_saturates_when_debt_exceeds_accrued() {
+        let mut pool = pool(1, 1, 3);
+        let mut state = state(1, pool.weight().unwrap());
+        pool.update(&mut state, &clock(0, 1)).unwrap();
+
+        let mut user = user(3);
+        user.lfg_reward_debt = 2;
+        user.reward_debts = [1; MAX_POOL_REWARDS];
+        user.fee_reward_debt = 1;
+        user.calculate_lfg_reward_amount(&pool).unwrap();
+        assert_eq!(user.lfg_reward_amount, 0);
+        assert_eq!(user.reward_amounts, [0; MAX_POOL_REWARDS]);
+        assert_eq!(user.fee_reward_amount, 0);
+    }
+
+    #[test]
+    fn update_accrues_only_inside_schedule() {
+        let mut pool = pool(1, 1, 1_000);
+        pool.start_time = 100;
+        let mut state = state(10, pool.weight().unwrap());
+        state.reward_end_time = 200;
+
+        pool.update(&mut state, &clock(0, 50)).unwrap();
+        assert_eq!(pool.acc_lfg_reward_per_share, 0);
+
+        pool.update(&mut state, &clock(0, 300)).unwrap();
+        assert_eq!(pool.acc_lfg_reward_per_share, 10 * 100 * ACC_PRECISION / 1_000);
+        assert_eq!(pool.last_reward_time, 300);
+    }
+
+    #[test]
+    fn slot_accrual_counts_only_slots_inside_schedule() {
+        let mut pool = pool(1, 1, 1_000);
+        pool.accrue_per_slot = true;
+        pool.start_time = 100;
+        let mut state = state(0, pool.weight().unwrap());
+        state.token_per_slot = 4;
+        state.reward_end_time = 300;
+
+        // 200 slots over 0..200, half of them before the start.
+        pool.update(&mut state, &clock(200, 200)).unwrap();
+        assert_eq!(state.lfg_accrued, 4 * 100);
+
+        // 200 slots over 200..400, half of them after the end.
+        pool.update(&mut state, &clock(400, 400)).unwrap();
+        assert_eq!(state.lfg_accrued, 4 * 200);
+
+        pool.update(&mut state, &clock(500, 500)).unwrap();
+        assert_eq!(state.lfg_accrued, 4 * 200);
+    }
+
+    #[test]
+    fn runway_counts_slot_emission() {
+        let mut state = state(10, 0);
+        state.token_per_slot = 2;
+        assert_eq!(state.emission_per_second(), 15);
+
+        state.reward_end_time = 100;
+        // 15 * 100 scheduled and nothing owed.
+        assert!(check_reward_solvency(&state, 0, 0, 1_500).is_ok());
+        assert_eq!(
+            check_reward_solvency(&state, 0, 0, 1_499).unwrap_err(),
+            ErrorCode::InsufficientRewardVault.into()
+        );
+
+        let old = state.emission_per_second();
+        state.token_per_slot = 0;
+        state.rescale_schedule(0, old, 0).unwrap();
+        assert_eq!(state.reward_end_time, 150);
+    }
+
+    #[test]
+    fn restarting_emission_schedules_unreserved_balance() {
+        let mut state = state(0, 0);
+        state.reward_end_time = 0;
+        state.lfg_accrued = 700;
+        state.lfg_paid = 200;
+
+        // Funded while emission was off: 2_000 in the vault, 500 of it owed.
+        state.token_per_second = 10;
+        state.rescale_schedule(100, 0, 2_000).unwrap();
+        assert_eq!(state.reward_end_time, 250);
+
+        // Stopping emission again leaves the end time alone.
+        let old = state.emission_per_second();
+        state.token_per_second = 0;
+        state.rescale_schedule(120, old, 2_000).unwrap();
+        assert_eq!(state.reward_end_time, 250);
+    }
+
+    #[test]
+    fn update_ignores_clock_running_backwards() {
+        let mut pool = pool(1, 1, 1_000);
+        let mut state = state(10, pool.weight().unwrap());
+        pool.update(&mut state, &clock(0, 100)).unwrap();
+        let acc = pool.acc_lfg_reward_per_share;
+
+        pool.update(&mut state, &clock(0, 40)).unwrap();
+        assert_eq!(pool.acc_lfg_reward_per_share, acc);
+        assert_eq!(pool.last_reward_time, 100);
+
+        pool.update(&mut state, &clock(0, 110)).unwrap();
+        assert_eq!(pool.acc_lfg_reward_per_share, acc + 10 * 10 * ACC_PRECISION / 1_000);
+    }
+
+    #[test]
+    fn settlement_pays_only_what_accrued_since_last_debt() {
+        let mut pool = pool(1, 1, 1_000);
+        let mut state = state(10, pool.weight().unwrap());
+        let mut user = user(1_000);
+
+        pool.update(&mut state, &clock(0, 10)).unwrap();
+        user.calculate_lfg_reward_amount(&pool).unwrap();
+        user.calculate_lfg_reward_debt(&pool).unwrap();
+        assert_eq!(user.lfg_reward_amount, 100);
+
+        pool.update(&mut state, &clock(0, 15)).unwrap();
+        user.calculate_lfg_reward_amount(&pool).unwrap();
+        assert_eq!(user.lfg_reward_amount, 150);
+    }
+
+    #[test]
+    fn reserve_counts_only_lfg_credited_to_stakers() {
+        let mut empty = pool(1, 1, 0);
+        let mut staked = pool(1, 1, 1_000);
+        let mut state = state(10, empty.weight().unwrap() + staked.weight().unwrap());
+        empty.update(&mut state, &clock(0, 10)).unwrap();
+        staked.update(&mut state, &clock(0, 10)).unwrap();
+        assert_eq!(state.lfg_accrued, 50);
+
+        let user = user(1_000);
+        assert_eq!(user.unsettled_lfg(&staked), 50);
+    }
+
+    #[test]
+    fn split_fee_follows_configured_shares() {
+        let mut state = state(0, 0);
+        state.fee_treasury_bps = 5_000;
+        state.fee_burn_bps = 2_000;
+        state.fee_redistribute_bps = 3_000;
+
+        let mut staked = pool(1, 1, 1_000);
+        assert_eq!(split_fee(&state, &mut staked, 100).unwrap(), (50, 20, 30));
+        assert_eq!(staked.acc_fee_per_share, 30 * ACC_PRECISION / 1_000);
+
+        let mut empty = pool(1, 1, 0);
+        assert_eq!(split_fee(&state, &mut empty, 100).unwrap(), (80, 20, 0));
+        assert_eq!(empty.acc_fee_per_share, 0);
+    }
+
+    #[test]
+    fn multiplier_change_keeps_redistributed_fees() {
+        let mut state = state(10, 0);
+        state.fee_redistribute_bps = BPS_DENOMINATOR;
+        let mut pool = pool(1, 1, 1_000);
+        state.total_weight = pool.weight().unwrap();
+        let mut user = user(1_000);
+
+        pool.update(&mut state, &clock(0, 10)).unwrap();
+        split_fee(&state, &mut pool, 30).unwrap();
+        pool.reweight(&mut state, 1, 5).unwrap();
+        assert_eq!(state.total_weight, 5);
+
+        pool.update(&mut state, &clock(0, 20)).unwrap();
+        user.calculate_lfg_reward_amount(&pool).unwrap();
+        assert_eq!(user.fee_reward_amount, 30);
+        assert_eq!(user.lfg_reward_amount, 200);
+    }
+
+    #[test]
+    fn checkpoint_reports_power_at_past_slots() {
+        let mut checkpoint = VoterCheckpoint::default();
+        assert_eq!(checkpoint.power_at(5).unwrap(), 0);
+
+        checkpoint.record(10, 100);
+        checkpoint.record(20, 200);
+        checkpoint.record(20, 250);
+        assert_eq!(checkpoint.len, 2);
+        assert_eq!(checkpoint.power_at(9).unwrap(), 0);
+        assert_eq!(checkpoint.power_at(10).unwrap(), 100);
+        assert_eq!(checkpoint.power_at(19).unwrap(), 100);
+        assert_eq!(checkpoint.power_at(25).unwrap(), 250);
+    }
+
+    #[test]
+    fn checkpoint_expires_overwritten_history() {
+        let mut checkpoint = VoterCheckpoint::default();
+        for slot in 1..=(VOTER_CHECKPOINTS as u64 + 2) {
+            checkpoint.record(slot, slot * 10);
+        }
+        assert_eq!(usize::from(checkpoint.len), VOTER_CHECKPOINTS);
+        assert_eq!(checkpoint.power_at(3).unwrap(), 30);
+        assert_eq!(checkpoint.power_at(1_000).unwrap(), (VOTER_CHECKPOINTS as u64 + 2) * 10);
+        assert_eq!(checkpoint.power_at(2).unwrap_err(), ErrorCode::CheckpointExpired.into());
+    }
+
+    #[test]
+    fn update_reports_math_overflow() {
+        let mut pool = pool(u64::MAX, 1, 1);
+        let mut state = state(u64::MAX, pool.weight().unwrap());
+        assert_eq!(
+            pool.update(&mut state, &clock(0, 10)).unwrap_err(),
+            ErrorCode::MathOverflow.into()
+        );
+    }
+
+    #[test]
+    fn update_reports_divide_by_zero() {
+        let mut pool = pool(1, 1, 1_000);
+        let mut state = state(10, 0);
+        assert_eq!(
+            pool.update(&mut state, &clock(0, 10)).unwrap_err(),
+            ErrorCode::DivideByZero.into()
+        );
+    }
+
+    #[test]
+    fn solvency_check_reports_insufficient_vault() {
+        let mut state = state(10, 0);
+        state.reward_end_time = 100;
+        state.lfg_accrued = 500;
+        state.lfg_paid = 200;
+
+        // 300 owed, 10 * 50 still scheduled and a 100 bonus.
+        assert!(check_reward_solvency(&state, 50, 100, 900).is_ok());
+        assert_eq!(
+            check_reward_solvency(&state, 50, 100, 899).unwrap_err(),
+            ErrorCode::InsufficientRewardVault.into()
+        );
+    }
+
+    #[test]
+    fn update_all_pools_reports_missing_pools() {
+        let mut state = state(10, 0);
+        state.pool_count = 1;
+        assert_eq!(
+            update_all_pools(&mut state, &clock(0, 10), &[], None).unwrap_err(),
+            ErrorCode::MissingPools.into()
+        );
+        assert!(update_all_pools(&mut state, &clock(0, 10), &[], Some(Pubkey::new_unique())).is_ok());
+    }
+
+    #[test]
+    fn bonus_seq_must_follow_the_last_batch() {
+        let mut state = state(0, 0);
+        state.bonus_seq = 5;
+        assert!(check_bonus_seq(&state, 6).is_ok());
+        assert_eq!(check_bonus_seq(&state, 5).unwrap_err(), ErrorCode::InvalidSEQ.into());
+        assert_eq!(check_bonus_seq(&state, 7).unwrap_err(), ErrorCode::InvalidSEQ.into());
+    }
+}